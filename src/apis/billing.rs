@@ -0,0 +1,398 @@
+use crate::apis::database::{ User, APIUsage, OperatorKey };
+use crate::apis::{ NocoDB, SQLite };
+use crate::apis::http::HttpClients;
+use crate::helper::metrics::metrics;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{ Arc, Mutex as StdMutex };
+
+use anyhow::{ Result, Context, bail };
+use async_trait::async_trait;
+use chrono::{ DateTime, Utc };
+use tokio::sync::{ Mutex, OwnedMutexGuard };
+
+
+/// Billing failures callers need to tell apart (e.g. to pick a status code).
+#[derive(Debug)]
+pub enum BillingError {
+    UnknownUser { api_key: String },
+    UserExists { api_key: String },
+    InsufficientBalance { balance: i32, reserved: i32, cost: i32 }
+}
+impl fmt::Display for BillingError {
+    fn fmt ( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            Self::UnknownUser { api_key } =>
+                write!(f, "User API key '{api_key}' does not exist!"),
+            Self::UserExists { api_key } =>
+                write!(f, "User API key `{api_key}` already exists!"),
+            Self::InsufficientBalance { balance, reserved, cost } =>
+                write!(f, "Balance {balance} ({reserved} reserved) is insufficient for cost {cost}!")
+        }
+    }
+}
+impl std::error::Error for BillingError {}
+
+/// Persistence for users, balances, usage logs and operator keys.
+///
+/// Implementations only need to provide the raw operations; reservation
+///  bookkeeping and per-user serialization live in `Billing`.
+#[async_trait]
+pub trait BillingStore: Send + Sync {
+    async fn verify_db ( &self ) -> Result<()>;
+    async fn get_user ( &self, user_api_key: String ) -> Result<User>;
+    async fn create_user ( &self, user: User ) -> Result<User>;
+    async fn offset_balance ( &self, user_api_key: String, amount: i32 ) -> Result<User>;
+    async fn create_api_usage_log (
+        &self,
+        api_usage_log: APIUsage,
+        user_api_key: String
+    ) -> Result<()>;
+
+    async fn create_operator_key ( &self, key: OperatorKey ) -> Result<OperatorKey>;
+    async fn get_operator_key ( &self, key_id: String ) -> Result<Option<OperatorKey>>;
+    async fn list_operator_keys ( &self ) -> Result<Vec<OperatorKey>>;
+    async fn revoke_operator_key ( &self, key_id: String, at: DateTime<Utc> ) -> Result<OperatorKey>;
+    async fn touch_operator_key ( &self, key_id: String, at: DateTime<Utc> ) -> Result<()>;
+}
+
+/// Builds the store selected by `BILLING_BACKEND` (`nocodb` or `sqlite`).
+pub fn store_from_env ( clients: &HttpClients ) -> Result<Arc<dyn BillingStore>> {
+    let backend = std::env::var("BILLING_BACKEND")
+        .unwrap_or_else(|_| String::from("nocodb"));
+
+    match backend.to_lowercase().as_str() {
+        "nocodb" => Ok(Arc::new(NocoDB::new(clients)?)),
+        "sqlite" => Ok(Arc::new(SQLite::new()?)),
+        other => bail!("Unknown BILLING_BACKEND `{other}`! Expected `nocodb` or `sqlite`.")
+    }
+}
+
+/// Cost held against a user's balance between the balance check and the
+///  provider call completing.
+///
+/// A reservation is released automatically when dropped, so any early
+///  return (a failed provider call, a bad PII type) frees the held amount.
+///  Only `Billing::commit_reservation` turns it into an actual deduction.
+#[derive(Debug)]
+pub struct Reservation {
+    pub user_api_key: String,
+    pub cost:         i32,
+
+    pending: Arc<StdMutex<HashMap<String, i32>>>,
+    settled: bool
+}
+impl Reservation {
+    pub fn release ( mut self ) {
+        self.settle();
+    }
+
+    /// Lowers the held amount to `cost`, e.g. when only part of a batch
+    ///  succeeded. The hold can only shrink; a larger `cost` is ignored.
+    pub fn reduce_to ( &mut self, cost: i32 ) {
+        let freed = self.cost - cost.max(0);
+        if self.settled || freed <= 0 {
+            return;
+        }
+
+        let mut pending = self.pending.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(held) = pending.get_mut(&self.user_api_key) {
+            *held -= freed;
+        }
+
+        self.cost -= freed;
+    }
+    fn settle ( &mut self ) {
+        if self.settled {
+            return;
+        }
+        self.settled = true;
+
+        let mut pending = self.pending.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(held) = pending.get_mut(&self.user_api_key) {
+            *held -= self.cost;
+
+            if *held <= 0 {
+                pending.remove(&self.user_api_key);
+            }
+        }
+    }
+}
+impl Drop for Reservation {
+    fn drop ( &mut self ) {
+        self.settle();
+    }
+}
+
+type UserLocks = StdMutex<HashMap<String, Arc<Mutex<()>>>>;
+
+// Held while reading and writing one user's balance. The user's lock is
+//  forgotten on drop once nobody else holds or waits on it.
+struct UserGuard<'a> {
+    locks:        &'a UserLocks,
+    user_api_key: String,
+    guard:        Option<OwnedMutexGuard<()>>
+}
+impl Drop for UserGuard<'_> {
+    fn drop ( &mut self ) {
+        // Release first, so the map holds the last reference if it's idle
+        drop(self.guard.take());
+
+        let mut locks = self.locks.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if locks.get(&self.user_api_key).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            locks.remove(&self.user_api_key);
+        }
+    }
+}
+
+// Only one API instance may share a store. NocoDB has no atomic increment,
+//  so balance writes are serialized through per-user locks, and in-flight
+//  reservations are tracked here until committed or released; both only
+//  exist in this process, so a second replica would race this one.
+pub struct Billing {
+    store: Arc<dyn BillingStore>,
+
+    user_locks: UserLocks,
+    pending:    Arc<StdMutex<HashMap<String, i32>>>
+}
+impl Billing {
+    pub fn new ( store: Arc<dyn BillingStore> ) -> Self {
+        Self {
+            store,
+            user_locks: StdMutex::new(HashMap::new()),
+            pending:    Arc::new(StdMutex::new(HashMap::new()))
+        }
+    }
+    async fn lock_user ( &self, user_api_key: &str ) -> UserGuard<'_> {
+        let lock = self.user_locks.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(user_api_key.to_owned())
+            .or_default()
+            .clone();
+
+        UserGuard {
+            locks:        &self.user_locks,
+            user_api_key: user_api_key.to_owned(),
+            guard:        Some(lock.lock_owned().await)
+        }
+    }
+    fn pending_for ( &self, user_api_key: &str ) -> i32 {
+        self.pending.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(user_api_key)
+            .copied()
+            .unwrap_or(0)
+    }
+    pub async fn verify_db ( &self ) -> Result<()> {
+        self.store.verify_db().await
+    }
+    pub async fn get_user ( &self, user_api_key: String ) -> Result<User> {
+        self.store.get_user(user_api_key).await
+    }
+    pub async fn create_user ( &self, user: User ) -> Result<User> {
+        self.store.create_user(user).await
+    }
+    pub async fn create_api_usage_log (
+        &self,
+        api_usage_log: APIUsage,
+        user_api_key: String
+    ) -> Result<()> {
+        self.store.create_api_usage_log(api_usage_log, user_api_key).await
+    }
+    pub async fn offset_balance ( &self, user_api_key: String, amount: i32 ) -> Result<User> {
+        let _guard = self.lock_user(&user_api_key).await;
+
+        self.store.offset_balance(user_api_key, amount).await
+    }
+    pub async fn reserve_balance ( &self, user_api_key: String, cost: i32 ) -> Result<Reservation> {
+        let _guard = self.lock_user(&user_api_key).await;
+
+        let user = self.store.get_user(user_api_key.clone()).await?;
+
+        // Funds already held by in-flight requests are not available
+        let held = self.pending_for(&user_api_key);
+        if user.balance - held < cost {
+            metrics().count_balance_rejection();
+
+            return Err(BillingError::InsufficientBalance {
+                balance:  user.balance,
+                reserved: held,
+                cost
+            }.into());
+        }
+
+        *self.pending.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(user_api_key.clone())
+            .or_insert(0) += cost;
+
+        Ok(Reservation {
+            user_api_key,
+            cost,
+            pending: self.pending.clone(),
+            settled: false
+        })
+    }
+    pub async fn commit_reservation ( &self, reservation: Reservation ) -> Result<User> {
+        let _guard = self.lock_user(&reservation.user_api_key).await;
+
+        // If the write fails, dropping the reservation releases the hold
+        let user = self.store
+            .offset_balance(reservation.user_api_key.clone(), -reservation.cost).await
+            .context("Failed to commit reservation!")?;
+
+        // Release while still holding the user's lock so that no reserve
+        //  can observe the deducted balance alongside the stale hold
+        reservation.release();
+
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apis::SQLite;
+
+    use std::time::Duration;
+
+    use axum::{
+        extract::{ Path, Query, State },
+        routing::get,
+        Json, Router
+    };
+    use serde_json::{ json, Value };
+
+    // The `api_keys` table of a local NocoDB: the adapter filters by key,
+    //  then PATCHes back an absolute balance, so unserialized writes would
+    //  lose updates just like against the real table.
+    type Table = Arc<StdMutex<Vec<User>>>;
+
+    async fn list_records ( State(table): State<Table>, Query(query): Query<HashMap<String, String>> ) -> Json<Value> {
+        let api_key = query.get("where")
+            .and_then(|filter| filter.strip_prefix("(api_key,eq,"))
+            .and_then(|filter| filter.strip_suffix(')'))
+            .map(str::to_owned);
+        let list: Vec<User> = table.lock().unwrap().iter()
+            .filter(|user| api_key.as_ref().is_none_or(|api_key| &user.api_key == api_key))
+            .cloned()
+            .collect();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        Json(json!({ "list": list, "pageInfo": { "isLastPage": true } }))
+    }
+    async fn create_record ( State(table): State<Table>, Path(table_id): Path<String>, Json(record): Json<Value> ) -> Json<Value> {
+        let mut users = table.lock().unwrap();
+        if table_id != "api_keys" {
+            return Json(json!({ "Id": 1 }));
+        }
+
+        let id = users.len() + 1;
+        users.push(User {
+            api_key: record["api_key"].as_str().unwrap().to_owned(),
+            balance: record["balance"].as_i64().unwrap() as i32,
+            id:      Some(id)
+        });
+
+        Json(json!({ "Id": id }))
+    }
+    async fn update_records ( State(table): State<Table>, Json(records): Json<Vec<Value>> ) -> Json<Value> {
+        let mut users = table.lock().unwrap();
+        for record in &records {
+            let user = users.iter_mut()
+                .find(|user| user.id == record["Id"].as_u64().map(|id| id as usize))
+                .unwrap();
+            user.balance = record["balance"].as_i64().unwrap() as i32;
+        }
+
+        Json(json!(records.iter().map(|record| json!({ "Id": record["Id"] })).collect::<Vec<_>>()))
+    }
+    async fn nocodb () -> (NocoDB, Table) {
+        let table = Table::default();
+        let mock = Router::new()
+            .route("/api/v2/tables/:table_id/records", get(list_records).post(create_record).patch(update_records))
+            .with_state(table.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, mock).await.unwrap() });
+
+        (NocoDB::at(format!("http://{address}")), table)
+    }
+
+    // Many tasks charge, fund and abandon lookups for one user at once; the
+    //  final balance must account for every committed change exactly.
+    async fn hammer ( store: Arc<dyn BillingStore> ) -> i32 {
+        const START: i32 = 100;
+        const COST: i32 = 3;
+        const TOP_UPS: i32 = 20;
+
+        let billing = Arc::new(Billing::new(store));
+        billing.create_user(User { api_key: "user".into(), balance: START, id: None }).await.unwrap();
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for index in 0..120 {
+            let billing = billing.clone();
+
+            tasks.spawn(async move {
+                match index % 6 {
+                    // Funding doesn't take a reservation
+                    0 if index / 6 < TOP_UPS as usize => {
+                        billing.offset_balance("user".into(), 1).await.unwrap();
+
+                        0
+                    },
+                    // A failed lookup drops its reservation
+                    1 => {
+                        let _reservation = billing.reserve_balance("user".into(), COST).await;
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+
+                        0
+                    },
+                    _ => {
+                        let Ok(reservation) = billing.reserve_balance("user".into(), COST).await else {
+                            return 0;
+                        };
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                        billing.commit_reservation(reservation).await.unwrap();
+
+                        1
+                    }
+                }
+            });
+        }
+
+        let mut committed = 0;
+        while let Some(outcome) = tasks.join_next().await {
+            committed += outcome.unwrap();
+        }
+
+        let balance = billing.get_user("user".into()).await.unwrap().balance;
+        assert!(committed > 0);
+        assert_eq!(balance, START + TOP_UPS - committed * COST);
+        assert!(balance >= 0);
+
+        // Nothing is left held or locked once every task is done
+        assert!(billing.pending.lock().unwrap().is_empty());
+        assert!(billing.user_locks.lock().unwrap().is_empty());
+
+        balance
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_charges_are_exact_against_nocodb () {
+        let (nocodb, table) = nocodb().await;
+        let balance = hammer(Arc::new(nocodb)).await;
+
+        // What the table itself ended up with, not the adapter's cached copy
+        assert_eq!(table.lock().unwrap()[0].balance, balance);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_charges_are_exact_against_sqlite () {
+        hammer(Arc::new(SQLite::open(":memory:").unwrap())).await;
+    }
+}
@@ -1,46 +1,93 @@
-use anyhow::{ Result, Context };
-use serde::{ Serialize, Deserialize };
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BulkVSPhoneNumberResponse {
-    pub name:   Option<String>,
-    pub number: Option<String>,
-    pub time:   Option<i64>
-}
-#[derive(Debug)]
-pub struct BulkVS {
-    api_key: String,
-}
-impl BulkVS {
-    pub fn new () -> Result<Self> {
-        Ok(Self {
-            api_key: std::env::var("BULKVS_API_KEY")
-                .context("Couldn't find API key in environment! Be sure to set `BULKVS_API_KEY`.")?
-        })
-    }
-    pub fn query_phone_number ( &self, phone_number: &str ) -> Result<BulkVSPhoneNumberResponse> {
-        // Build a proxied `ureq` client
-        let proxy = ureq::Proxy::new(&std::env::var("PROXY_LINK")
-            .context("PROXY_LINK not set!")?)?;
-        let agent = ureq::AgentBuilder::new()
-            .proxy(proxy)
-            .build();
-
-        let path = format!(
-            "https://cnam.bulkvs.com/?id={}&did={}&format=json",
-            self.api_key,
-            phone_number);
-
-        let resp_object = agent.get(&path)
-            .call()
-            .map_err(|e| anyhow::anyhow!("Failed to query CNAM lookup backend! {:?}", e))?;
-
-        let resp_object_string = resp_object.into_string()
-            .context("Failed to convert response into string!")?;
-
-        let res: BulkVSPhoneNumberResponse = serde_json::from_str(&resp_object_string)
-            .context("Failed to deserialize response!")?;
-
-        Ok(res)
-    }
-}
\ No newline at end of file
+use crate::apis::http::{ HttpClients, timeout_from_env };
+use crate::apis::limits::concurrency_from_env;
+use crate::apis::resilience::Resilience;
+use crate::helper::types::{ PII, Provider };
+use crate::helper::fields::FieldSchema;
+use crate::helper::findings::{ Finding, Identity, Normalize };
+use crate::helper::request_id;
+
+use std::time::Duration;
+
+use anyhow::{ Result, Context };
+use serde::{ Serialize, Deserialize };
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkVSPhoneNumberResponse {
+    pub name:   Option<String>,
+    pub number: Option<String>,
+    pub time:   Option<i64>
+}
+impl Normalize for BulkVSPhoneNumberResponse {
+    fn normalize ( &self, _schema: &FieldSchema ) -> Vec<Finding> {
+        if self.name.is_none() && self.number.is_none() {
+            return Vec::new();
+        }
+
+        vec!(Finding {
+            provider: Provider::BulkVS,
+            source:   Some(String::from("cnam")),
+            url:      None,
+            observed: self.time
+                .and_then(|time| chrono::DateTime::from_timestamp(time, 0))
+                .map(|time| time.to_rfc3339()),
+            identity: Identity {
+                name:  self.name.clone(),
+                phone: self.number.clone(),
+                ..Identity::default()
+            }
+        })
+    }
+}
+#[derive(Debug)]
+pub struct BulkVS {
+    api_key: String,
+    client:  reqwest::Client,
+    timeout: Duration,
+    permits: Semaphore,
+
+    pub resilience: Resilience
+}
+impl BulkVS {
+    /// PII types the CNAM lookup accepts.
+    pub const LOOKUP_TYPES: &'static [PII] = &[ PII::Phone ];
+
+    pub fn new ( clients: &HttpClients ) -> Result<Self> {
+        Ok(Self {
+            api_key: std::env::var("BULKVS_API_KEY")
+                .context("Couldn't find API key in environment! Be sure to set `BULKVS_API_KEY`.")?,
+            client:  clients.proxied.clone(),
+            timeout: timeout_from_env("BULKVS_TIMEOUT_SECS", 15)?,
+            permits: concurrency_from_env("BULKVS_MAX_CONCURRENCY", 4)?,
+
+            resilience: Resilience::from_env(Provider::BulkVS, 250)?
+        })
+    }
+    pub async fn query_phone_number ( &self, phone_number: &str ) -> Result<BulkVSPhoneNumberResponse> {
+        // Taken per attempt, so a call waiting out a retry's backoff doesn't hold one
+        self.resilience.call("cnam", || async move {
+            let _permit = self.permits.acquire().await
+                .context("BulkVS concurrency limiter was closed!")?;
+
+            let resp_object = request_id::forward(self.client.get("https://cnam.bulkvs.com/"))
+                .query(&[
+                    ("id", self.api_key.as_str()),
+                    ("did", phone_number),
+                    ("format", "json")
+                ])
+                .timeout(self.timeout)
+                .send().await
+                .context("Failed to query CNAM lookup backend!")?
+                .error_for_status()
+                .context("CNAM lookup backend returned an error status!")?;
+
+            let resp_object_string = resp_object.text().await
+                .context("Failed to convert response into string!")?;
+
+            let res: BulkVSPhoneNumberResponse = serde_json::from_str(&resp_object_string)
+                .context("Failed to deserialize response!")?;
+
+            Ok(res)
+        }).await
+    }
+}
@@ -0,0 +1,281 @@
+use crate::apis::http::timeout_from_env;
+use crate::helper::types::{ PII, Provider };
+use crate::apis::health::describe;
+use crate::helper::metrics::metrics;
+
+use std::num::NonZeroUsize;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use anyhow::{ Result, Context, anyhow };
+use lru::LruCache;
+use rusqlite::{ Connection, OptionalExtension, params };
+use serde::{ Serialize, de::DeserializeOwned };
+use serde_json::Value;
+
+
+/// Identifies one provider lookup, e.g. a Snusbase email search.
+///
+/// Terms are normalized so trivially different spellings of the same
+///  lookup (surrounding whitespace, case where it doesn't matter) share
+///  an entry.
+#[derive(Debug, Clone)]
+pub struct CacheKey {
+    pub provider: Provider,
+    key:          String,
+    preview:      bool
+}
+impl CacheKey {
+    pub fn new (
+        provider:  Provider,
+        operation: &str,
+        pii_type:  &PII,
+        term:      &str
+    ) -> Self {
+        let term = term.trim();
+        let term = match pii_type {
+            PII::Email | PII::Domain | PII::Hash => term.to_lowercase(),
+            _ => term.to_owned()
+        };
+
+        Self {
+            provider,
+            key: format!("{}:{operation}:{}:{term}", provider.as_str(), pii_type.as_str()),
+            preview: false
+        }
+    }
+    /// Marks the lookup as a tally preview, whose result is kept for at
+    ///  least `TALLY_RETENTION_SECS` even if the provider's TTL is shorter.
+    pub fn for_preview ( mut self ) -> Self {
+        self.preview = true;
+
+        self
+    }
+}
+
+/// A cache hit, with how much longer it stays fresh.
+#[derive(Debug)]
+pub struct CacheHit<T> {
+    pub value:     T,
+    pub remaining: Duration
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    value:      Value,
+    expires_at: u64
+}
+
+/// Provider responses, kept for a per-provider TTL in an in-memory LRU
+///  and, if `CACHE_SQLITE_PATH` is set, in an on-disk SQLite table that
+///  survives restarts.
+///
+/// Each TTL is read from `<PROVIDER>_CACHE_TTL_SECS`; zero disables
+///  caching for that provider.
+pub struct ResponseCache {
+    memory: Mutex<LruCache<String, Entry>>,
+    disk:   Option<Arc<Mutex<Connection>>>,
+
+    snusbase_ttl: Duration,
+    bulkvs_ttl:   Duration,
+    sherlock_ttl: Duration,
+
+    tally_retention: Duration
+}
+impl ResponseCache {
+    pub fn new () -> Result<Self> {
+        let capacity = match std::env::var("CACHE_MAX_ENTRIES") {
+            Ok(value) => value.parse::<usize>()
+                .context("CACHE_MAX_ENTRIES must be a whole number!")?,
+            Err(_) => 10_000
+        };
+        let capacity = NonZeroUsize::new(capacity)
+            .context("CACHE_MAX_ENTRIES must be at least 1!")?;
+
+        let disk = match std::env::var("CACHE_SQLITE_PATH") {
+            Ok(path) if !path.is_empty() => Some(Arc::new(Mutex::new(Self::open_disk(&path)?))),
+            _ => None
+        };
+
+        Ok(Self {
+            memory: Mutex::new(LruCache::new(capacity)),
+            disk,
+
+            snusbase_ttl: timeout_from_env("SNUSBASE_CACHE_TTL_SECS", 60 * 60)?,
+            bulkvs_ttl:   timeout_from_env("BULKVS_CACHE_TTL_SECS", 24 * 60 * 60)?,
+            sherlock_ttl: timeout_from_env("SHERLOCK_CACHE_TTL_SECS", 60 * 60)?,
+
+            tally_retention: timeout_from_env("TALLY_RETENTION_SECS", 5 * 60)?
+        })
+    }
+    fn open_disk ( path: &str ) -> Result<Connection> {
+        let connection = Connection::open(path)
+            .context(format!("Failed to open cache database at `{path}`!"))?;
+
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS response_cache (
+                key        TEXT    PRIMARY KEY,
+                value      TEXT    NOT NULL,
+                expires_at INTEGER NOT NULL
+            );"
+        ).context("Failed to create the response cache table!")?;
+        connection.execute("DELETE FROM response_cache WHERE expires_at <= ?1", params![now()])
+            .context("Failed to prune the response cache!")?;
+
+        Ok(connection)
+    }
+
+    pub fn ttl ( &self, provider: Provider ) -> Duration {
+        match provider {
+            Provider::Snusbase => self.snusbase_ttl,
+            Provider::BulkVS   => self.bulkvs_ttl,
+            Provider::Sherlock => self.sherlock_ttl
+        }
+    }
+
+    /// Looks up a fresh entry, checking memory before disk. Cache failures
+    ///  are reported and treated as a miss rather than failing the lookup.
+    ///
+    /// Entries only exist for a provider with caching disabled if a tally
+    ///  retained them, so those are still looked up.
+    pub async fn get<T: DeserializeOwned> ( &self, key: &CacheKey ) -> Option<CacheHit<T>> {
+        let entry = match self.get_memory(&key.key) {
+            Some(entry) => Some(entry),
+            None => match self.get_disk(&key.key).await {
+                Ok(entry) => {
+                    // Promote disk hits so the next one is served from memory
+                    if let Some(entry) = &entry {
+                        self.memory.lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .put(key.key.clone(), entry.clone());
+                    }
+
+                    entry
+                },
+                Err(e) => {
+                    tracing::warn!(error = %describe(&e), "Failed to read from the response cache");
+
+                    None
+                }
+            }
+        };
+
+        let hit = entry.and_then(|entry| match serde_json::from_value(entry.value) {
+            Ok(value) => Some(CacheHit {
+                value,
+                remaining: Duration::from_secs(entry.expires_at.saturating_sub(now()))
+            }),
+            Err(e) => {
+                // Not the error itself; serde's can quote the value
+                tracing::warn!(category = ?e.classify(), "Discarding unreadable response cache entry");
+
+                None
+            }
+        });
+        metrics().count_cache(key.provider, hit.is_some());
+
+        hit
+    }
+    fn get_memory ( &self, key: &str ) -> Option<Entry> {
+        let mut memory = self.memory.lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        match memory.get(key) {
+            Some(entry) if entry.expires_at > now() => Some(entry.clone()),
+            Some(_) => {
+                memory.pop(key);
+
+                None
+            },
+            None => None
+        }
+    }
+    async fn get_disk ( &self, key: &str ) -> Result<Option<Entry>> {
+        let Some(disk) = self.disk.clone() else {
+            return Ok(None);
+        };
+        let key = key.to_owned();
+
+        tokio::task::spawn_blocking(move || {
+            let connection = disk.lock()
+                .map_err(|_| anyhow!("Cache connection lock was poisoned!"))?;
+
+            let row: Option<(String, u64)> = connection
+                .query_row(
+                    "SELECT value, expires_at FROM response_cache WHERE key = ?1 AND expires_at > ?2",
+                    params![key, now()],
+                    |row| Ok((row.get(0)?, row.get(1)?))
+                )
+                .optional()
+                .context("Failed to query the response cache!")?;
+
+            row.map(|(value, expires_at)| Ok(Entry {
+                value: serde_json::from_str(&value)
+                    .context("Failed to parse cached response!")?,
+                expires_at
+            })).transpose()
+        }).await
+            .context("Cache task panicked!")?
+    }
+
+    /// Stores a response for its provider's TTL (or the tally retention
+    ///  window, if longer and `key` is a preview), returning that TTL.
+    pub async fn put<T: Serialize> ( &self, key: &CacheKey, value: &T ) -> Duration {
+        let ttl = match key.preview {
+            true  => self.ttl(key.provider).max(self.tally_retention),
+            false => self.ttl(key.provider)
+        };
+        if ttl.is_zero() {
+            return ttl;
+        }
+
+        let value = match serde_json::to_value(value) {
+            Ok(value) => value,
+            Err(e) => {
+                tracing::warn!(category = ?e.classify(), "Failed to serialize response for the cache");
+
+                return ttl;
+            }
+        };
+        let entry = Entry {
+            value,
+            expires_at: now() + ttl.as_secs()
+        };
+
+        self.memory.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .put(key.key.clone(), entry.clone());
+
+        if let Err(e) = self.put_disk(key.key.clone(), entry).await {
+            tracing::warn!(error = %describe(&e), "Failed to write to the response cache");
+        }
+
+        ttl
+    }
+    async fn put_disk ( &self, key: String, entry: Entry ) -> Result<()> {
+        let Some(disk) = self.disk.clone() else {
+            return Ok(());
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let connection = disk.lock()
+                .map_err(|_| anyhow!("Cache connection lock was poisoned!"))?;
+
+            connection.execute(
+                "INSERT INTO response_cache (key, value, expires_at) VALUES (?1, ?2, ?3)
+                    ON CONFLICT(key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+                params![key, entry.value.to_string(), entry.expires_at]
+            ).context("Failed to write to the response cache!")?;
+
+            Ok(())
+        }).await
+            .context("Cache task panicked!")?
+    }
+}
+
+fn now () -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
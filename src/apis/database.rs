@@ -1,8 +1,22 @@
-use crate::helper::types::PII;
+use crate::helper::types::{ PII, Scope };
+use crate::helper::request_id;
 
-use serde::{Deserialize, Serialize};
+use crate::apis::billing::{ BillingStore, BillingError };
+use crate::apis::operators::is_key_id;
+use crate::apis::http::{ HttpClients, timeout_from_env };
+use crate::apis::limits::concurrency_from_env;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use serde::{ Deserialize, Serialize, de::DeserializeOwned };
 use anyhow::{ Result, anyhow, Context };
+use chrono::{ DateTime, Utc };
 use serde_json::{ json, Value };
+use async_trait::async_trait;
+use reqwest::{ Method, RequestBuilder, Response };
+use tokio::sync::Semaphore;
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct User {
@@ -18,10 +32,92 @@ pub struct APIUsage {
     pub pii_type: PII,
     pub pii:      String,
     pub cost:     i32,
+    /// The API request that incurred it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
     #[serde(rename = "Id")]
     pub id:      Option<usize>
 }
 
+/// An operator (staff/integration) credential. Only a hash of the secret
+///  is ever stored; `id` is the public part of the key used for lookup.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct OperatorKey {
+    pub id:           String,
+    pub name:         String,
+    #[serde(skip)]
+    pub key_hash:     String,
+    pub scopes:       Vec<Scope>,
+    pub created_at:   DateTime<Utc>,
+    pub expires_at:   Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at:   Option<DateTime<Utc>>
+}
+impl OperatorKey {
+    /// Scopes as stored in a text column, e.g. `db:query,tally:read`.
+    pub fn scopes_to_string ( scopes: &[Scope] ) -> String {
+        scopes.iter()
+            .map(Scope::as_str)
+            .collect::<Vec<&str>>()
+            .join(",")
+    }
+    pub fn scopes_from_str ( scopes: &str ) -> Result<Vec<Scope>> {
+        scopes.split(',')
+            .map(str::trim)
+            .filter(|scope| !scope.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+/// Largest page NocoDB will serve for a single records request.
+const NOCODB_PAGE_SIZE: usize = 1000;
+
+/// An `operator_keys` row as NocoDB returns it; timestamps are RFC 3339 text.
+#[derive(Debug, Deserialize)]
+struct NocoDBOperatorKey {
+    #[serde(rename = "Id")]
+    row_id:       usize,
+    key_id:       String,
+    name:         String,
+    key_hash:     String,
+    scopes:       String,
+    created_at:   String,
+    expires_at:   Option<String>,
+    last_used_at: Option<String>,
+    revoked_at:   Option<String>
+}
+impl NocoDBOperatorKey {
+    fn into_operator_key ( self ) -> Result<OperatorKey> {
+        fn parse ( timestamp: &str ) -> Result<DateTime<Utc>> {
+            Ok(DateTime::parse_from_rfc3339(timestamp)
+                .context(format!("Invalid timestamp `{timestamp}`!"))?
+                .with_timezone(&Utc))
+        }
+        fn parse_optional ( timestamp: Option<String> ) -> Result<Option<DateTime<Utc>>> {
+            timestamp
+                .filter(|timestamp| !timestamp.is_empty())
+                .map(|timestamp| parse(&timestamp))
+                .transpose()
+        }
+
+        Ok(OperatorKey {
+            id:           self.key_id,
+            name:         self.name,
+            key_hash:     self.key_hash,
+            scopes:       OperatorKey::scopes_from_str(&self.scopes)?,
+            created_at:   parse(&self.created_at)?,
+            expires_at:   parse_optional(self.expires_at)?,
+            last_used_at: parse_optional(self.last_used_at)?,
+            revoked_at:   parse_optional(self.revoked_at)?
+        })
+    }
+}
+#[derive(Debug)]
+struct CachedUser {
+    user:       User,
+    fetched_at: Instant
+}
 #[derive(Debug)]
 pub struct NocoDB {
     api_key:                 String,
@@ -30,9 +126,24 @@ pub struct NocoDB {
     api_keys_table_id:       String,
     api_usage_table_id:      String,
     api_usage_link_field_id: String,
+    operator_keys_table_id:  Option<String>,
+    /// The usage table's column for request IDs, if it has one.
+    api_usage_request_id_field: Option<String>,
+
+    client:  reqwest::Client,
+    timeout: Duration,
+    permits: Semaphore,
+
+    user_cache:     Mutex<HashMap<String, CachedUser>>,
+    user_cache_ttl: Duration
 }
 impl NocoDB {
-    pub fn new() -> Result<Self> {
+    pub fn new( clients: &HttpClients ) -> Result<Self> {
+        let user_cache_ttl = std::env::var("USER_CACHE_TTL_SECS")
+            .unwrap_or_else(|_| String::from("30"))
+            .parse::<u64>()
+            .context("USER_CACHE_TTL_SECS must be a whole number of seconds")?;
+
         Ok(Self {
             api_key: std::env::var("NOCODB_API_KEY")
                 .context("NOCODB_API_KEY must be set")?,
@@ -43,81 +154,224 @@ impl NocoDB {
             api_usage_table_id: std::env::var("API_USAGE_TABLE_ID")
                 .context("API_USAGE_TABLE_ID must be set")?,
             api_usage_link_field_id: std::env::var("API_USAGE_LINK_FIELD_ID")
-                .context("API_USAGE_LINK_FIELD_ID must be set")?
+                .context("API_USAGE_LINK_FIELD_ID must be set")?,
+            operator_keys_table_id: std::env::var("OPERATOR_KEYS_TABLE_ID").ok(),
+            api_usage_request_id_field: std::env::var("API_USAGE_REQUEST_ID_FIELD").ok(),
+            client:  clients.direct.clone(),
+            timeout: timeout_from_env("NOCODB_TIMEOUT_SECS", 10)?,
+            permits: concurrency_from_env("NOCODB_MAX_CONCURRENCY", 16)?,
+            user_cache:     Mutex::new(HashMap::new()),
+            user_cache_ttl: Duration::from_secs(user_cache_ttl)
         })
     }
-    pub fn verify_db ( &self ) -> Result<()> {
-        let url = format!("{}/api/v2/tables/{}/records", self.base_url, self.api_keys_table_id);
+    #[cfg(test)]
+    pub fn at ( base_url: String ) -> Self {
+        Self {
+            api_key: String::from("test"),
+            base_url,
+            api_keys_table_id:       String::from("api_keys"),
+            api_usage_table_id:      String::from("api_usage"),
+            api_usage_link_field_id: String::from("usage"),
+            operator_keys_table_id:  None,
+            api_usage_request_id_field: None,
+            client:  reqwest::Client::new(),
+            timeout: Duration::from_secs(10),
+            permits: Semaphore::new(16),
+            user_cache:     Mutex::new(HashMap::new()),
+            user_cache_ttl: Duration::from_secs(30)
+        }
+    }
+    fn request ( &self, method: Method, url: &str ) -> RequestBuilder {
+        request_id::forward(self.client.request(method, url))
+            .header("xc-token", &self.api_key)
+            .timeout(self.timeout)
+    }
+    async fn send ( &self, request: RequestBuilder ) -> Result<Response> {
+        let _permit = self.permits.acquire().await
+            .context("NocoDB concurrency limiter was closed!")?;
 
-        // Use the `ureq` crate to send a POST request to the database
-        let _ = ureq::get(&url)
-            .set("xc-token", &self.api_key)
-            .call()
-            .context("Failed to send the request!")?;
+        request
+            .send().await
+            .context("Failed to send the request!")?
+            .error_for_status()
+            .context("NocoDB returned an error status!")
+    }
 
-        Ok(())
+    /* User cache */
+    fn cached_user ( &self, user_api_key: &str ) -> Option<User> {
+        let cache = self.user_cache.lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        cache.get(user_api_key)
+            .filter(|cached| cached.fetched_at.elapsed() < self.user_cache_ttl)
+            .map(|cached| cached.user.clone())
+    }
+    fn cache_user ( &self, user: &User ) {
+        self.user_cache.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(user.api_key.clone(), CachedUser {
+                user:       user.clone(),
+                fetched_at: Instant::now()
+            });
+    }
+    fn invalidate_user ( &self, user_api_key: &str ) {
+        self.user_cache.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(user_api_key);
     }
 
-    /* Interfaces for the `api_keys` table */
-    pub fn get_users ( &self ) -> Result<Vec<User>> {
-        let url = format!("{}/api/v2/tables/{}/records", self.base_url, self.api_keys_table_id);
+    /* Generic table interfaces */
+    async fn get_records_page<T: DeserializeOwned> (
+        &self,
+        table_id: &str,
+        filter:   Option<&str>,
+        offset:   usize,
+        limit:    usize
+    ) -> Result<(Vec<T>, bool)> {
+        let url = format!("{}/api/v2/tables/{}/records", self.base_url, table_id);
 
-        // Use the `ureq` crate to send a POST request to the database
-        let response = ureq::get(&url)
-            .set("xc-token", &self.api_key)
-            .call()
-            .context("Failed to send the request!")?;
+        // Send a GET request to the database
+        let mut request = self.request(Method::GET, &url)
+            .query(&[("offset", offset), ("limit", limit)]);
+        if let Some(filter) = filter {
+            request = request.query(&[("where", filter)]);
+        }
+        let response = self.send(request).await?;
 
-        let response_string = response.into_string()
+        let response_string = response.text().await
             .context("Failed to convert response into string!")?;
 
         let response_value = serde_json::from_str::<Value>(&response_string)
             .context("Response was not valid JSON!")?;
         
-        let users_value = response_value.get("list")
+        let records_value = response_value.get("list")
             .context("Response was missing `list` field!")?;
 
-        let users: Vec<User> = serde_json::from_value(users_value.clone())
+        let records: Vec<T> = serde_json::from_value(records_value.clone())
             .context("Failed to deserialize response!")?;
 
-        Ok(users)
+        // Older NocoDB versions omit `pageInfo`; a short page is the last one
+        let is_last_page = response_value.get("pageInfo")
+            .and_then(|page_info| page_info.get("isLastPage"))
+            .and_then(Value::as_bool)
+            .unwrap_or(records.len() < limit);
+
+        Ok((records, is_last_page))
     }
-    pub fn get_user ( &self, user_api_key: String ) -> Result<User> {
-        let users = self.get_users()?;
+    async fn get_all_records<T: DeserializeOwned> ( &self, table_id: &str ) -> Result<Vec<T>> {
+        let mut records = Vec::new();
+
+        loop {
+            let (page, is_last_page) = self.get_records_page(table_id, None, records.len(), NOCODB_PAGE_SIZE).await?;
+            let page_len = page.len();
 
-        for user in &users {
-            if user.api_key == user_api_key {
-                return Ok((*user).clone());
+            records.extend(page);
+
+            if is_last_page || page_len == 0 {
+                break;
             }
         }
 
-        Err(anyhow!("User API key '{}' does not exist!", &user_api_key).into())
+        Ok(records)
     }
-    pub fn create_user ( &self, user: User ) -> Result<User> {
-        // First, verify that the user does not exist
-        let users = self.get_users()?;
 
-        for current_user in &users {
-            if current_user.api_key == user.api_key {
-                return Err(anyhow!("User API key `{}` already exists!", user.api_key).into());
-            }
+    /* Interfaces for the `api_keys` table */
+    pub async fn get_users ( &self ) -> Result<Vec<User>> {
+        self.get_all_records(&self.api_keys_table_id).await
+    }
+    /// Looks a user up by key directly in NocoDB, bypassing the cache.
+    async fn fetch_user ( &self, user_api_key: &str ) -> Result<Option<User>> {
+        // NocoDB's `where` syntax has no escaping, so keys containing its
+        //  delimiters can only be found by scanning every page
+        let users = if user_api_key.contains([',', '(', ')', '~']) {
+            self.get_users().await?
+        } else {
+            self.get_records_page(&self.api_keys_table_id, Some(&format!("(api_key,eq,{user_api_key})")), 0, 2).await?.0
+        };
+
+        let user = users.into_iter()
+            .find(|user| user.api_key == user_api_key);
+        if let Some(user) = &user {
+            self.cache_user(user);
+        }
+
+        Ok(user)
+    }
+
+    /* Interfaces for the `operator_keys` table */
+    fn operator_keys_table_id ( &self ) -> Result<&str> {
+        self.operator_keys_table_id.as_deref()
+            .context("OPERATOR_KEYS_TABLE_ID must be set to manage operator keys in NocoDB")
+    }
+    async fn fetch_operator_key_row ( &self, key_id: &str ) -> Result<Option<NocoDBOperatorKey>> {
+        // Anything but a generated hex ID could rewrite the `where`
+        if !is_key_id(key_id) {
+            return Ok(None);
+        }
+
+        let rows: Vec<NocoDBOperatorKey> = self.get_records_page(
+            self.operator_keys_table_id()?,
+            Some(&format!("(key_id,eq,{key_id})")),
+            0, 2
+        ).await?.0;
+
+        Ok(rows.into_iter().find(|row| row.key_id == key_id))
+    }
+    async fn patch_operator_key ( &self, key_id: &str, fields: Value ) -> Result<OperatorKey> {
+        let row = self.fetch_operator_key_row(key_id).await?
+            .context(format!("Operator key `{key_id}` does not exist!"))?;
+
+        let mut patch = fields;
+        patch["Id"] = json!(row.row_id);
+
+        let url = format!("{}/api/v2/tables/{}/records", self.base_url, self.operator_keys_table_id()?);
+        self.send(self.request(Method::PATCH, &url)
+            .json(&json!([patch]))).await?;
+
+        self.fetch_operator_key_row(key_id).await?
+            .context(format!("Operator key `{key_id}` disappeared while updating!"))?
+            .into_operator_key()
+    }
+}
+
+#[async_trait]
+impl BillingStore for NocoDB {
+    async fn verify_db ( &self ) -> Result<()> {
+        let url = format!("{}/api/v2/tables/{}/records", self.base_url, self.api_keys_table_id);
+
+        // Send a GET request to the database
+        let _ = self.send(self.request(Method::GET, &url)
+            .query(&[("limit", 1)])).await?;
+
+        Ok(())
+    }
+
+    async fn get_user ( &self, user_api_key: String ) -> Result<User> {
+        if let Some(user) = self.cached_user(&user_api_key) {
+            return Ok(user);
+        }
+
+        self.fetch_user(&user_api_key).await?
+            .ok_or_else(|| BillingError::UnknownUser { api_key: user_api_key }.into())
+    }
+    async fn create_user ( &self, user: User ) -> Result<User> {
+        // First, verify that the user does not exist
+        if self.fetch_user(&user.api_key).await?.is_some() {
+            return Err(BillingError::UserExists { api_key: user.api_key }.into());
         }
 
         let mut user = user;
 
         let url = format!("{}/api/v2/tables/{}/records", self.base_url, self.api_keys_table_id);
 
-        // Use the `ureq` crate to send a POST request to the database
-        let response = ureq::post(&url)
-            .set("xc-token", &self.api_key)
-            .set("Content-Type", "application/json")
-            .send_json(json!({
+        // Send a POST request to the database
+        let response = self.send(self.request(Method::POST, &url)
+            .json(&json!({
                 "api_key": user.api_key,
                 "balance": user.balance
-            }))
-            .context("Failed to send the request!")?;
+            }))).await?;
 
-        let response_string = response.into_string()
+        let response_string = response.text().await
             .context("Failed to convert response into string!")?;
         
         let response_value = serde_json::from_str::<Value>(&response_string)
@@ -128,30 +382,33 @@ impl NocoDB {
         
         user.id = Some(serde_json::from_value(user_id.clone())
             .context("Failed to deserialize response!")?);
+
+        self.cache_user(&user);
         
         Ok(user)
     }
-    pub fn offset_balance ( &self, user_api_key: String, amount: i32 ) -> Result<User> {
-        // Verify the user exists
-        let mut user = self.get_user(user_api_key)
-            .context("User does not exist!")?;
+    async fn offset_balance ( &self, user_api_key: String, amount: i32 ) -> Result<User> {
+        // Always read the current balance from NocoDB itself, since the
+        //  absolute value written back must not be based on a stale copy.
+        //  This is still a read-modify-write: `Billing` serializes it per
+        //  user, which only holds while a single instance uses the table.
+        self.invalidate_user(&user_api_key);
+        let mut user = self.fetch_user(&user_api_key).await?
+            .ok_or(BillingError::UnknownUser { api_key: user_api_key })?;
         
         user.balance = (user.balance + amount).max(0);
 
         let url = format!("{}/api/v2/tables/{}/records", self.base_url, self.api_keys_table_id);
 
         // Send the PATCH request
-        let response = ureq::patch(&url)
-            .set("xc-token", &self.api_key)
-            .set("Content-Type", "application/json")
-            .send_json(json!([{
+        let response = self.send(self.request(Method::PATCH, &url)
+            .json(&json!([{
                 "Id":      user.id,
                 "api_key": user.api_key,
                 "balance": user.balance
-            }]))
-            .context("Failed to send the request!");
+            }]))).await?;
         
-        let response_string = response?.into_string()
+        let response_string = response.text().await
             .context("Failed to convert response into string!")?;
 
         let response_value = serde_json::from_str::<Value>(&response_string)
@@ -161,14 +418,16 @@ impl NocoDB {
         let _ = response_value
             .as_array()
             .context("Response was not an array!")?
-            .get(0)
+            .first()
             .context("Response was missing first element!")?
             .get("Id")
             .context("Response was missing `Id` field!")?;
 
+        self.cache_user(&user);
+
         Ok(user)
     }
-    pub fn create_api_usage_log (
+    async fn create_api_usage_log (
         &self,
         api_usage_log: APIUsage,
         user_api_key: String
@@ -178,20 +437,22 @@ impl NocoDB {
         // Build the log creation URL
         let create_log_url = format!("{}/api/v2/tables/{}/records", self.base_url, self.api_usage_table_id);
 
-        // Use the `ureq` crate to send a POST request to the database
-        let response = ureq::post(&create_log_url)
-            .set("xc-token", &self.api_key)
-            .set("Content-Type", "application/json")
-            .send_json(json!({
-                "category": log.category,
-                "service":  log.service,
-                "pii_type": log.pii_type,
-                "pii":      log.pii,
-                "cost":     log.cost
-            }))
-            .context("Failed to send the request!")?;
-
-        let response_string = response.into_string()
+        let mut record = json!({
+            "category": log.category,
+            "service":  log.service,
+            "pii_type": log.pii_type,
+            "pii":      log.pii,
+            "cost":     log.cost
+        });
+        if let (Some(field), Some(request_id)) = (&self.api_usage_request_id_field, &log.request_id) {
+            record[field] = json!(request_id);
+        }
+
+        // Send a POST request to the database
+        let response = self.send(self.request(Method::POST, &create_log_url)
+            .json(&record)).await?;
+
+        let response_string = response.text().await
             .context("Failed to convert response into string!")?;
         
         let response_value = serde_json::from_str::<Value>(&response_string)
@@ -205,8 +466,7 @@ impl NocoDB {
             .context("Failed to deserialize response!")?);
 
         // Get the user's ID
-        let user = self.get_user(user_api_key)
-            .context("User does not exist!")?;
+        let user = self.get_user(user_api_key).await?;
         
         // Build the table link URL
         let link_log_url = format!(
@@ -217,22 +477,54 @@ impl NocoDB {
             user.id.context("User ID was not set!")?
         );
 
-        // Use the `ureq` crate to send a POST request to the database
-        let response = ureq::post(&link_log_url)
-            .set("xc-token", &self.api_key)
-            .set("Content-Type", "application/json")
-            .send_json(json!([{
+        // Send a POST request to the database
+        let response = self.send(self.request(Method::POST, &link_log_url)
+            .json(&json!([{
                 "Id": log.id.context("Log ID was not set!")?,
-            }]))
-            .context("Failed to send the request!")?;
+            }]))).await?;
         
-        let response_string = response.into_string()
+        let response_string = response.text().await
             .context("Failed to convert response into string!")?;
 
         if response_string != "true" {
-            return Err(anyhow!("Failed to link the log to the user!").into());
+            return Err(anyhow!("Failed to link the log to the user!"));
         }
         
         Ok(())
     }
+
+    async fn create_operator_key ( &self, key: OperatorKey ) -> Result<OperatorKey> {
+        let url = format!("{}/api/v2/tables/{}/records", self.base_url, self.operator_keys_table_id()?);
+
+        self.send(self.request(Method::POST, &url)
+            .json(&json!({
+                "key_id":     key.id,
+                "name":       key.name,
+                "key_hash":   key.key_hash,
+                "scopes":     OperatorKey::scopes_to_string(&key.scopes),
+                "created_at": key.created_at.to_rfc3339(),
+                "expires_at": key.expires_at.map(|at| at.to_rfc3339())
+            }))).await?;
+
+        Ok(key)
+    }
+    async fn get_operator_key ( &self, key_id: String ) -> Result<Option<OperatorKey>> {
+        self.fetch_operator_key_row(&key_id).await?
+            .map(NocoDBOperatorKey::into_operator_key)
+            .transpose()
+    }
+    async fn list_operator_keys ( &self ) -> Result<Vec<OperatorKey>> {
+        self.get_all_records::<NocoDBOperatorKey>(self.operator_keys_table_id()?).await?
+            .into_iter()
+            .map(NocoDBOperatorKey::into_operator_key)
+            .collect()
+    }
+    async fn revoke_operator_key ( &self, key_id: String, at: DateTime<Utc> ) -> Result<OperatorKey> {
+        self.patch_operator_key(&key_id, json!({ "revoked_at": at.to_rfc3339() })).await
+    }
+    async fn touch_operator_key ( &self, key_id: String, at: DateTime<Utc> ) -> Result<()> {
+        self.patch_operator_key(&key_id, json!({ "last_used_at": at.to_rfc3339() })).await?;
+
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,215 @@
+use crate::apis::resilience::is_transient;
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use chrono::{ DateTime, Utc };
+use serde::Serialize;
+
+
+/// How many of a provider's most recent call latencies are kept.
+const LATENCY_SAMPLES: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    /// Nothing has been asked of the provider yet.
+    Unknown,
+    Up,
+    /// The last call to the provider failed through its own fault.
+    Down
+}
+
+/// Percentiles over the provider's recent calls, in milliseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct Latency {
+    pub samples: usize,
+    pub p50_ms:  u64,
+    pub p90_ms:  u64,
+    pub p99_ms:  u64
+}
+
+/// How a provider's calls have been going, as of the last one.
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthStatus {
+    pub state:        HealthState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error:   Option<String>,
+    pub consecutive_failures: u32,
+    pub calls:        u64,
+    pub failures:     u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency:      Option<Latency>
+}
+
+#[derive(Debug)]
+struct Tracked {
+    status:    HealthStatus,
+    latencies: VecDeque<Duration>
+}
+
+/// Tracks one provider's health from the outcome of each call made to it.
+#[derive(Debug)]
+pub struct ProviderHealth {
+    tracked: Mutex<Tracked>
+}
+impl ProviderHealth {
+    pub fn new () -> Self {
+        Self {
+            tracked: Mutex::new(Tracked {
+                status: HealthStatus {
+                    state:        HealthState::Unknown,
+                    last_success: None,
+                    last_failure: None,
+                    last_error:   None,
+                    consecutive_failures: 0,
+                    calls:        0,
+                    failures:     0,
+                    latency:      None
+                },
+                latencies: VecDeque::with_capacity(LATENCY_SAMPLES)
+            })
+        }
+    }
+
+    /// Runs one call to the provider, recording how long it took and how
+    ///  it went.
+    pub async fn observe<T> ( &self, call: impl Future<Output = anyhow::Result<T>> ) -> anyhow::Result<T> {
+        let started = Instant::now();
+        let outcome = call.await;
+
+        let mut tracked = self.lock();
+        if tracked.latencies.len() == LATENCY_SAMPLES {
+            tracked.latencies.pop_front();
+        }
+        tracked.latencies.push_back(started.elapsed());
+        tracked.status.calls += 1;
+        drop(tracked);
+
+        self.record(outcome)
+    }
+    /// Records `outcome` and passes it through, e.g. for a connectivity
+    ///  check that isn't a call worth timing.
+    ///
+    /// As with the circuit breaker, only transient failures count against
+    ///  the provider; a rejected query or bad response doesn't mean it's down.
+    pub fn record<T> ( &self, outcome: anyhow::Result<T> ) -> anyhow::Result<T> {
+        let mut tracked = self.lock();
+        let status = &mut tracked.status;
+
+        match &outcome {
+            Ok(_) => {
+                status.state = HealthState::Up;
+                status.last_success = Some(Utc::now());
+                status.consecutive_failures = 0;
+            },
+            Err(e) if is_transient(e) => {
+                status.state = HealthState::Down;
+                status.last_failure = Some(Utc::now());
+                status.last_error = Some(describe(e));
+                status.consecutive_failures += 1;
+                status.failures += 1;
+            },
+            Err(_) => ()
+        }
+
+        outcome
+    }
+
+    pub fn status ( &self ) -> HealthStatus {
+        let tracked = self.lock();
+
+        let mut latencies: Vec<Duration> = tracked.latencies.iter().copied().collect();
+        latencies.sort();
+        let percentile = |p: usize| latencies
+            .get((latencies.len() * p / 100).min(latencies.len() - 1))
+            .map_or(0, |latency| latency.as_millis() as u64);
+
+        HealthStatus {
+            latency: (!latencies.is_empty()).then(|| Latency {
+                samples: latencies.len(),
+                p50_ms:  percentile(50),
+                p90_ms:  percentile(90),
+                p99_ms:  percentile(99)
+            }),
+            ..tracked.status.clone()
+        }
+    }
+
+    fn lock ( &self ) -> std::sync::MutexGuard<'_, Tracked> {
+        self.tracked.lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl Default for ProviderHealth {
+    fn default () -> Self {
+        Self::new()
+    }
+}
+
+/// The error's chain, minus any request URL and any JSON error's detail;
+///  some providers take their API key as a query parameter, serde quotes
+///  the values it choked on, and this ends up on the status page.
+pub fn describe ( error: &anyhow::Error ) -> String {
+    error.chain()
+        .map(|cause| {
+            if let Some(e) = cause.downcast_ref::<serde_json::Error>() {
+                return format!("invalid JSON ({:?} error at line {} column {})", e.classify(), e.line(), e.column());
+            }
+
+            match cause.downcast_ref::<reqwest::Error>() {
+                Some(e) if e.is_timeout() => "request timed out".to_string(),
+                Some(e) if e.is_connect() => "connection failed".to_string(),
+                Some(e) => match e.status() {
+                    Some(status) => format!("status {status}"),
+                    None => "request failed".to_string()
+                },
+                None => cause.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Context;
+
+    #[test]
+    fn json_errors_are_described_without_the_payload () {
+        let error = serde_json::from_str::<u32>(r#""alice@example.com""#)
+            .context("Failed to parse response from Snusbase!")
+            .unwrap_err();
+
+        let described = describe(&error);
+        assert!(!described.contains("alice"), "{described}");
+        assert_eq!(described, "Failed to parse response from Snusbase!: invalid JSON (Data error at line 1 column 19)");
+    }
+
+    #[test]
+    fn only_transient_failures_mark_a_provider_down () {
+        let health = ProviderHealth::new();
+        let _ = health.record(Ok(()));
+
+        // A rejected query is the caller's problem, not the provider's
+        let _ = health.record::<()>(Err(anyhow::anyhow!("Snusbase rejected the query!")));
+        let status = health.status();
+        assert_eq!(status.state, HealthState::Up);
+        assert_eq!(status.consecutive_failures, 0);
+
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        let _ = health.record::<()>(Err(anyhow::Error::new(refused).context("Failed to reach Snusbase!")));
+        let status = health.status();
+        assert_eq!(status.state, HealthState::Down);
+        assert_eq!(status.consecutive_failures, 1);
+        assert_eq!(status.last_error.as_deref(), Some("Failed to reach Snusbase!: connection refused"));
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use anyhow::{ Result, Context };
+
+
+/// Connection-pooled HTTP clients shared by every adapter.
+///
+/// `proxied` routes through the SOCKS proxy in `PROXY_LINK` and is used
+///  for third-party providers; `direct` is used for our own services.
+#[derive(Debug, Clone)]
+pub struct HttpClients {
+    pub proxied: reqwest::Client,
+    pub direct:  reqwest::Client,
+    /// Kept so readiness checks can tell whether the proxy is up.
+    pub proxy_link: String
+}
+impl HttpClients {
+    pub fn new () -> Result<Self> {
+        let proxy_link = std::env::var("PROXY_LINK")
+            .context("PROXY_LINK not set!")?;
+        let proxy = reqwest::Proxy::all(&proxy_link)
+            .context("PROXY_LINK is not a valid proxy URL!")?;
+
+        let proxied = reqwest::Client::builder()
+            .proxy(proxy)
+            .connect_timeout(Duration::from_secs(10))
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build()
+            .context("Failed to build proxied HTTP client!")?;
+        let direct = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build()
+            .context("Failed to build HTTP client!")?;
+
+        Ok(Self { proxied, direct, proxy_link })
+    }
+}
+
+/// Reads a per-provider request timeout in seconds, e.g. `SNUSBASE_TIMEOUT_SECS`.
+pub fn timeout_from_env ( var: &str, default_secs: u64 ) -> Result<Duration> {
+    let secs = match std::env::var(var) {
+        Ok(value) => value.parse::<u64>()
+            .context(format!("{var} must be a whole number of seconds!"))?,
+        Err(_) => default_secs
+    };
+
+    Ok(Duration::from_secs(secs))
+}
@@ -0,0 +1,194 @@
+use crate::apis::http::timeout_from_env;
+use crate::apis::snusbase::{ SnusbaseDBResponse, SnusbaseHashLookupResponse, SnusbaseIPResponse };
+use crate::apis::bulkvs::BulkVSPhoneNumberResponse;
+use crate::apis::sherlock::SherlockResponse;
+use crate::helper::fields::FieldSchema;
+use crate::helper::findings::{ Finding, Normalize };
+use crate::helper::types::{ API, PII };
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{ DateTime, Utc };
+use serde::Serialize;
+use tokio::sync::watch;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled
+}
+impl JobStatus {
+    pub fn is_finished ( &self ) -> bool {
+        *self != JobStatus::Running
+    }
+}
+
+/// The raw response of whichever lookup a job ran.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum LookupResult {
+    Db(SnusbaseDBResponse),
+    Hashes(SnusbaseHashLookupResponse),
+    Geo(SnusbaseIPResponse),
+    Cnam(BulkVSPhoneNumberResponse),
+    Profiles(SherlockResponse)
+}
+impl Normalize for LookupResult {
+    fn normalize ( &self, schema: &FieldSchema ) -> Vec<Finding> {
+        match self {
+            LookupResult::Db(res)       => res.normalize(schema),
+            LookupResult::Hashes(res)   => res.normalize(schema),
+            LookupResult::Geo(res)      => res.normalize(schema),
+            LookupResult::Cnam(res)     => res.normalize(schema),
+            LookupResult::Profiles(res) => res.normalize(schema)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobError {
+    pub code:  &'static str,
+    pub error: String
+}
+
+/// A lookup running in the background, independent of the request that
+///  submitted it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id:       String,
+    pub api:      API,
+    pub pii_type: PII,
+    pub term:     String,
+    pub status:   JobStatus,
+    /// Results found so far, for lookups that report them as they go.
+    pub found:    usize,
+    /// Held against the user's balance until the job finishes.
+    pub reserved: i32,
+    /// What was actually charged, once the lookup settled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost:     Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error:    Option<JobError>,
+
+    pub created_at:  DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+
+    /// The user API key the job is billed to; only they can see it.
+    #[serde(skip)]
+    pub owner:  String,
+    #[serde(skip)]
+    pub result: Option<LookupResult>
+}
+
+struct Entry {
+    job:    Job,
+    cancel: watch::Sender<bool>
+}
+
+/// Every job submitted since startup, kept in memory until
+///  `JOB_RETENTION_SECS` after it finishes.
+pub struct Jobs {
+    entries:   Mutex<HashMap<String, Entry>>,
+    retention: Duration
+}
+impl Jobs {
+    pub fn new () -> Result<Self> {
+        Ok(Self {
+            entries:   Mutex::new(HashMap::new()),
+            retention: timeout_from_env("JOB_RETENTION_SECS", 60 * 60)?
+        })
+    }
+
+    /// Adds a job, returning the flag its task watches for a cancel.
+    pub fn insert ( &self, job: Job ) -> watch::Receiver<bool> {
+        let mut entries = self.lock();
+        self.prune(&mut entries);
+
+        let (cancel, cancelled) = watch::channel(false);
+        entries.insert(job.id.clone(), Entry { job, cancel });
+
+        cancelled
+    }
+    /// The job, if it exists and belongs to `owner`.
+    pub fn get ( &self, id: &str, owner: &str ) -> Option<Job> {
+        let mut entries = self.lock();
+        self.prune(&mut entries);
+
+        entries.get(id)
+            .filter(|entry| entry.job.owner == owner)
+            .map(|entry| entry.job.clone())
+    }
+    pub fn update ( &self, id: &str, update: impl FnOnce(&mut Job) ) {
+        if let Some(entry) = self.lock().get_mut(id) {
+            update(&mut entry.job);
+        }
+    }
+    /// Records how a job ended. A job cancelled while it was settling
+    ///  stays cancelled, but still shows what it was charged.
+    pub fn finish ( &self, id: &str, outcome: Result<(i32, LookupResult), JobError> ) {
+        self.update(id, |job| {
+            let cancelled = job.status == JobStatus::Cancelled;
+
+            match outcome {
+                Ok((cost, result)) => {
+                    job.cost = Some(cost);
+                    if !cancelled {
+                        job.status = JobStatus::Succeeded;
+                        job.result = Some(result);
+                    }
+                },
+                Err(error) => if !cancelled {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(error);
+                }
+            }
+
+            job.finished_at.get_or_insert_with(Utc::now);
+        });
+    }
+    /// How many jobs are still running.
+    pub fn running ( &self ) -> usize {
+        self.lock().values()
+            .filter(|entry| !entry.job.status.is_finished())
+            .count()
+    }
+    /// Flags a running job as cancelled. Its task stops if it's still
+    ///  waiting on the provider, releasing the reservation; one already
+    ///  settling is left to finish, so billing is never cut off halfway.
+    ///  Returns the job as it was left, or `None` if `owner` has no such job.
+    pub fn cancel ( &self, id: &str, owner: &str ) -> Option<Job> {
+        let mut entries = self.lock();
+
+        let entry = entries.get_mut(id)
+            .filter(|entry| entry.job.owner == owner)?;
+        if !entry.job.status.is_finished() {
+            entry.cancel.send_replace(true);
+            entry.job.status = JobStatus::Cancelled;
+            entry.job.finished_at = Some(Utc::now());
+        }
+
+        Some(entry.job.clone())
+    }
+
+    fn lock ( &self ) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries.lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+    fn prune ( &self, entries: &mut HashMap<String, Entry> ) {
+        let Ok(retention) = chrono::Duration::from_std(self.retention) else {
+            return;
+        };
+        let cutoff = Utc::now() - retention;
+
+        entries.retain(|_, entry| entry.job.finished_at
+            .is_none_or(|finished_at| finished_at > cutoff));
+    }
+}
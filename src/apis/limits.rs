@@ -0,0 +1,19 @@
+use anyhow::{ Result, Context, bail };
+use tokio::sync::Semaphore;
+
+
+/// Builds the semaphore bounding in-flight calls to one provider, sized by
+///  e.g. `SNUSBASE_MAX_CONCURRENCY`.
+pub fn concurrency_from_env ( var: &str, default_permits: usize ) -> Result<Semaphore> {
+    let permits = match std::env::var(var) {
+        Ok(value) => value.parse::<usize>()
+            .context(format!("{var} must be a whole number!"))?,
+        Err(_) => default_permits
+    };
+
+    if permits == 0 {
+        bail!("{var} must be at least 1!");
+    }
+
+    Ok(Semaphore::new(permits))
+}
@@ -1,9 +1,24 @@
-pub mod snusbase;
-pub mod bulkvs;
-pub mod sherlock;
-pub mod database;
-
-pub use snusbase::Snusbase;
-pub use bulkvs::BulkVS;
-pub use sherlock::Sherlock;
-pub use database::NocoDB;
\ No newline at end of file
+pub mod snusbase;
+pub mod bulkvs;
+pub mod sherlock;
+pub mod database;
+pub mod sqlite;
+pub mod billing;
+pub mod http;
+pub mod limits;
+pub mod operators;
+pub mod cache;
+pub mod jobs;
+pub mod health;
+pub mod resilience;
+
+pub use snusbase::Snusbase;
+pub use bulkvs::BulkVS;
+pub use sherlock::Sherlock;
+pub use database::NocoDB;
+pub use sqlite::SQLite;
+pub use billing::Billing;
+pub use http::HttpClients;
+pub use operators::Operators;
+pub use cache::ResponseCache;
+pub use jobs::Jobs;
\ No newline at end of file
@@ -0,0 +1,419 @@
+use crate::apis::billing::BillingStore;
+use crate::apis::database::OperatorKey;
+use crate::apis::health::describe;
+use crate::apis::limits::concurrency_from_env;
+use crate::helper::types::Scope;
+use crate::helper::request_id;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+
+use anyhow::{ Result, Context, anyhow };
+use argon2::{
+    Argon2,
+    PasswordHash,
+    PasswordHasher,
+    PasswordVerifier,
+    password_hash::{
+        SaltString,
+        rand_core::{ OsRng, RngCore }
+    }
+};
+use chrono::{ DateTime, Utc };
+use serde::Serialize;
+use sha2::{ Sha256, Digest };
+use subtle::ConstantTimeEq;
+use tokio::sync::Semaphore;
+
+
+/// Prefix of every minted operator key: `osk_<id>_<secret>`.
+const KEY_PREFIX: &str = "osk_";
+
+/// How long a successful Argon2 verification is trusted before the key is
+///  re-read from the store. Revocations made through this process take
+///  effect immediately; ones made by another instance within this window.
+const VERIFIED_KEY_TTL: Duration = Duration::from_secs(60);
+
+/// Why an operator key was rejected.
+#[derive(Debug)]
+pub enum OperatorKeyError {
+    Invalid,
+    Revoked,
+    Expired,
+    MissingScope(Scope),
+    UnknownKey { key_id: String }
+}
+impl fmt::Display for OperatorKeyError {
+    fn fmt ( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            Self::Invalid               => write!(f, "Invalid API key!"),
+            Self::Revoked               => write!(f, "API key has been revoked!"),
+            Self::Expired               => write!(f, "API key has expired!"),
+            Self::MissingScope(scope)   => write!(f, "API key is missing the `{}` scope!", scope.as_str()),
+            Self::UnknownKey { key_id } => write!(f, "Operator key `{key_id}` does not exist!")
+        }
+    }
+}
+impl std::error::Error for OperatorKeyError {}
+
+/// A freshly minted key. `key` is the only time the plaintext is available.
+#[derive(Debug, Serialize)]
+pub struct MintedKey {
+    pub key:          String,
+    pub operator_key: OperatorKey
+}
+
+#[derive(Debug)]
+struct VerifiedKey {
+    digest:       [u8; 32],
+    operator_key: OperatorKey,
+    verified_at:  Instant
+}
+
+pub struct Operators {
+    store: Arc<dyn BillingStore>,
+
+    bootstrap_digest: Option<[u8; 32]>,
+    started_at:       DateTime<Utc>,
+
+    verified: Mutex<HashMap<String, VerifiedKey>>,
+    // Checked against for unknown key IDs, so they take as long to reject
+    //  as a wrong secret and timing doesn't tell which IDs exist
+    dummy_hash: String,
+    // Argon2 is deliberately slow; bounds how many hashes run at once, so
+    //  a flood of bad keys can't take every core
+    hashing:  Arc<Semaphore>
+}
+impl Operators {
+    pub fn new ( store: Arc<dyn BillingStore> ) -> Result<Self> {
+        // An optional all-scopes key for minting the first real keys
+        let bootstrap_digest = std::env::var("BOOTSTRAP_API_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+            .map(|key| digest(&key));
+
+        Ok(Self {
+            store,
+            bootstrap_digest,
+            started_at: Utc::now(),
+            verified:   Mutex::new(HashMap::new()),
+            dummy_hash: hash_secret(&to_hex(&random_bytes::<32>()))?,
+            hashing:    Arc::new(concurrency_from_env("OPERATOR_KEY_MAX_HASHING", 2)?)
+        })
+    }
+    pub async fn mint (
+        &self,
+        name:       String,
+        scopes:     Vec<Scope>,
+        expires_at: Option<DateTime<Utc>>
+    ) -> Result<MintedKey> {
+        let id = to_hex(&random_bytes::<8>());
+        let secret = to_hex(&random_bytes::<32>());
+
+        let permit = self.hashing.clone().acquire_owned().await
+            .context("Key hashing limiter was closed!")?;
+        let key_hash = tokio::task::spawn_blocking({
+            let secret = secret.clone();
+
+            // The permit goes with the work, which outlives a dropped request
+            move || {
+                let _permit = permit;
+
+                hash_secret(&secret)
+            }
+        }).await
+            .context("Key hashing task panicked!")??;
+
+        let operator_key = self.store.create_operator_key(OperatorKey {
+            id: id.clone(),
+            name,
+            key_hash,
+            scopes,
+            created_at:   Utc::now(),
+            expires_at,
+            last_used_at: None,
+            revoked_at:   None
+        }).await?;
+
+        Ok(MintedKey {
+            key: format!("{KEY_PREFIX}{id}_{secret}"),
+            operator_key
+        })
+    }
+    pub async fn list ( &self ) -> Result<Vec<OperatorKey>> {
+        self.store.list_operator_keys().await
+    }
+    pub async fn revoke ( &self, key_id: String ) -> Result<OperatorKey> {
+        if !is_key_id(&key_id) || self.store.get_operator_key(key_id.clone()).await?.is_none() {
+            return Err(OperatorKeyError::UnknownKey { key_id }.into());
+        }
+
+        let operator_key = self.store.revoke_operator_key(key_id.clone(), Utc::now()).await?;
+
+        self.verified.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&key_id);
+
+        Ok(operator_key)
+    }
+
+    /// Resolves a presented key to its record, checking revocation,
+    ///  expiry and that it carries `scope`.
+    pub async fn authenticate (
+        &self,
+        presented: &str,
+        scope:     Scope
+    ) -> Result<OperatorKey> {
+        let operator_key = self.verify(presented).await?;
+
+        if operator_key.revoked_at.is_some() {
+            return Err(OperatorKeyError::Revoked.into());
+        }
+        if operator_key.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+            return Err(OperatorKeyError::Expired.into());
+        }
+        if !operator_key.scopes.contains(&scope) {
+            return Err(OperatorKeyError::MissingScope(scope).into());
+        }
+
+        Ok(operator_key)
+    }
+    async fn verify ( &self, presented: &str ) -> Result<OperatorKey> {
+        let presented_digest = digest(presented);
+
+        if let Some(bootstrap_digest) = &self.bootstrap_digest {
+            if bool::from(presented_digest.ct_eq(bootstrap_digest)) {
+                return Ok(self.bootstrap_key());
+            }
+        }
+
+        let (id, secret) = presented.strip_prefix(KEY_PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+            .ok_or(OperatorKeyError::Invalid)?;
+        // The ID goes into a store query, so nothing else gets that far
+        if !is_key_id(id) {
+            return Err(OperatorKeyError::Invalid.into());
+        }
+
+        // Serve recently verified keys without re-running Argon2
+        if let Some(verified) = self.verified.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(id)
+            .filter(|verified| verified.verified_at.elapsed() < VERIFIED_KEY_TTL)
+        {
+            return if bool::from(verified.digest.ct_eq(&presented_digest)) {
+                Ok(verified.operator_key.clone())
+            } else {
+                Err(OperatorKeyError::Invalid.into())
+            };
+        }
+
+        let operator_key = self.store.get_operator_key(id.to_owned()).await?;
+
+        let key_hash = operator_key.as_ref()
+            .map_or_else(|| self.dummy_hash.clone(), |operator_key| operator_key.key_hash.clone());
+        let secret = secret.to_owned();
+        let permit = self.hashing.clone().acquire_owned().await
+            .context("Key hashing limiter was closed!")?;
+        let is_valid = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            PasswordHash::new(&key_hash)
+                .map(|hash| Argon2::default().verify_password(secret.as_bytes(), &hash).is_ok())
+                .unwrap_or(false)
+        }).await
+            .context("Key verification task panicked!")?;
+
+        let Some(operator_key) = operator_key.filter(|_| is_valid) else {
+            return Err(OperatorKeyError::Invalid.into());
+        };
+
+        self.verified.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id.to_owned(), VerifiedKey {
+                digest:       presented_digest,
+                operator_key: operator_key.clone(),
+                verified_at:  Instant::now()
+            });
+
+        // Recording usage is best-effort and at most once per verification
+        let store = self.store.clone();
+        let key_id = id.to_owned();
+        tokio::spawn(request_id::propagate(async move {
+            if let Err(e) = store.touch_operator_key(key_id, Utc::now()).await {
+                tracing::warn!(error = %describe(&e), "Failed to record operator key usage");
+            }
+        }));
+
+        Ok(operator_key)
+    }
+    fn bootstrap_key ( &self ) -> OperatorKey {
+        OperatorKey {
+            id:           String::from("bootstrap"),
+            name:         String::from("BOOTSTRAP_API_KEY"),
+            key_hash:     String::new(),
+            scopes:       Scope::ALL.to_vec(),
+            created_at:   self.started_at,
+            expires_at:   None,
+            last_used_at: None,
+            revoked_at:   None
+        }
+    }
+}
+
+/// Whether `id` could be a minted key ID: 16 lowercase hex characters.
+pub fn is_key_id ( id: &str ) -> bool {
+    id.len() == 16 && id.chars().all(|ch| matches!(ch, '0'..='9' | 'a'..='f'))
+}
+
+fn hash_secret ( secret: &str ) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Failed to hash key: {e}"))
+}
+fn random_bytes<const N: usize> () -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+
+    bytes
+}
+fn digest ( key: &str ) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+fn to_hex ( bytes: &[u8] ) -> String {
+    bytes.iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apis::SQLite;
+
+    fn operators ( bootstrap: Option<&str> ) -> Operators {
+        Operators {
+            bootstrap_digest: bootstrap.map(digest),
+            ..Operators::new(Arc::new(SQLite::open(":memory:").unwrap())).unwrap()
+        }
+    }
+    fn rejection ( outcome: Result<OperatorKey> ) -> OperatorKeyError {
+        outcome.unwrap_err()
+            .downcast::<OperatorKeyError>()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn minted_keys_authenticate_with_their_scopes_only () {
+        let operators = operators(None);
+        let minted = operators.mint("ci".into(), vec!(Scope::DbQuery), None).await.unwrap();
+
+        let operator_key = operators.authenticate(&minted.key, Scope::DbQuery).await.unwrap();
+        assert_eq!(operator_key.id, minted.operator_key.id);
+        // Again, now from the verified cache
+        operators.authenticate(&minted.key, Scope::DbQuery).await.unwrap();
+
+        assert!(matches!(
+            rejection(operators.authenticate(&minted.key, Scope::UsersWrite).await),
+            OperatorKeyError::MissingScope(Scope::UsersWrite)
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_unknown_and_malformed_keys_are_invalid () {
+        let operators = operators(None);
+        let minted = operators.mint("ci".into(), vec!(Scope::DbQuery), None).await.unwrap();
+        let id = &minted.operator_key.id;
+
+        for presented in [
+            format!("{KEY_PREFIX}{id}_{}", "0".repeat(64)),
+            format!("{KEY_PREFIX}{}_{}", "0123456789abcdef", "0".repeat(64)),
+            format!("{KEY_PREFIX}{id}"),
+            format!("{KEY_PREFIX}(id,neq,x)_secret"),
+            String::from("not a key")
+        ] {
+            assert!(matches!(
+                rejection(operators.authenticate(&presented, Scope::DbQuery).await),
+                OperatorKeyError::Invalid
+            ), "{presented}");
+        }
+    }
+
+    // An unknown ID still runs Argon2, so it can't be told from a wrong secret by timing
+    #[tokio::test]
+    async fn unknown_key_ids_take_as_long_as_wrong_secrets () {
+        let operators = operators(None);
+        let minted = operators.mint("ci".into(), vec!(Scope::DbQuery), None).await.unwrap();
+
+        let time = |presented: String| {
+            let operators = &operators;
+
+            async move {
+                let started = Instant::now();
+                let _ = operators.authenticate(&presented, Scope::DbQuery).await;
+
+                started.elapsed()
+            }
+        };
+        let wrong_secret = time(format!("{KEY_PREFIX}{}_{}", minted.operator_key.id, "0".repeat(64))).await;
+        let unknown_id = time(format!("{KEY_PREFIX}{}_{}", "0123456789abcdef", "0".repeat(64))).await;
+
+        assert!(unknown_id * 2 > wrong_secret, "unknown {unknown_id:?}, wrong {wrong_secret:?}");
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_refused () {
+        let operators = operators(None);
+        let expired = operators.mint("ci".into(), vec!(Scope::DbQuery), Some(Utc::now() - chrono::Duration::minutes(1))).await.unwrap();
+        let current = operators.mint("ci".into(), vec!(Scope::DbQuery), Some(Utc::now() + chrono::Duration::hours(1))).await.unwrap();
+
+        assert!(matches!(
+            rejection(operators.authenticate(&expired.key, Scope::DbQuery).await),
+            OperatorKeyError::Expired
+        ));
+        operators.authenticate(&current.key, Scope::DbQuery).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn revoked_keys_are_refused_at_once () {
+        let operators = operators(None);
+        let minted = operators.mint("ci".into(), vec!(Scope::DbQuery), None).await.unwrap();
+        operators.authenticate(&minted.key, Scope::DbQuery).await.unwrap();
+
+        let revoked = operators.revoke(minted.operator_key.id.clone()).await.unwrap();
+        assert!(revoked.revoked_at.is_some());
+        assert!(matches!(
+            rejection(operators.authenticate(&minted.key, Scope::DbQuery).await),
+            OperatorKeyError::Revoked
+        ));
+
+        for key_id in [ "0123456789abcdef", "not-an-id" ] {
+            let error = operators.revoke(key_id.into()).await.unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(OperatorKeyError::UnknownKey { .. })), "{key_id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn the_bootstrap_key_has_every_scope () {
+        let operators = operators(Some("boot"));
+
+        for scope in Scope::ALL {
+            let operator_key = operators.authenticate("boot", scope).await.unwrap();
+            assert_eq!(operator_key.id, "bootstrap");
+        }
+        assert!(matches!(
+            rejection(operators.authenticate("boot2", Scope::DbQuery).await),
+            OperatorKeyError::Invalid
+        ));
+
+        // Without one configured, nothing is a bootstrap key
+        assert!(matches!(
+            rejection(self::operators(None).authenticate("boot", Scope::DbQuery).await),
+            OperatorKeyError::Invalid
+        ));
+    }
+}
@@ -0,0 +1,375 @@
+use crate::apis::health::{ ProviderHealth, describe };
+use crate::helper::pricing::number_from_env;
+use crate::helper::types::Provider;
+use crate::helper::metrics::metrics;
+
+use std::fmt;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use anyhow::{ Result, bail };
+use serde::Serialize;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Calls go through.
+    Closed,
+    /// The provider kept failing; calls are refused until it's retried.
+    Open,
+    /// One trial call is let through to see if the provider recovered.
+    HalfOpen
+}
+impl BreakerState {
+    /// As a gauge value: 0 closed, 1 half-open, 2 open.
+    pub fn level ( &self ) -> u8 {
+        match self {
+            BreakerState::Closed   => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open     => 2
+        }
+    }
+}
+
+/// A call refused because the provider's breaker is open.
+#[derive(Debug)]
+pub struct BreakerOpen {
+    pub provider:    Provider,
+    pub retry_after: Duration
+}
+impl fmt::Display for BreakerOpen {
+    fn fmt ( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        write!(
+            f, "{} is failing, so its circuit breaker is open; try again in {}s!",
+            self.provider.as_str(), self.retry_after.as_secs().max(1)
+        )
+    }
+}
+impl std::error::Error for BreakerOpen {}
+
+#[derive(Debug)]
+struct Breaker {
+    state:    BreakerState,
+    failures: u32,
+    /// When an open breaker next lets a trial call through.
+    reopens:  Instant
+}
+
+/// Opens after `threshold` consecutive failed calls, refusing calls for
+///  `open_for`, then lets one trial call decide whether to close again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    provider:  Provider,
+    breaker:   Mutex<Breaker>,
+    threshold: u32,
+    open_for:  Duration
+}
+impl CircuitBreaker {
+    pub fn state ( &self ) -> BreakerState {
+        self.lock().state
+    }
+    /// Refuses early if a call would be refused, without taking the trial
+    ///  call; e.g. before reserving a user's balance for it.
+    pub fn check ( &self ) -> Result<(), BreakerOpen> {
+        let breaker = self.lock();
+
+        match breaker.state {
+            BreakerState::Open if Instant::now() < breaker.reopens => Err(self.refusal(&breaker)),
+            _ => Ok(())
+        }
+    }
+    fn admit ( &self ) -> Result<Admission<'_>, BreakerOpen> {
+        let mut breaker = self.lock();
+
+        match breaker.state {
+            BreakerState::Closed => Ok(Admission { breaker: self, trial: false, settled: false }),
+            BreakerState::Open if Instant::now() >= breaker.reopens => {
+                breaker.state = BreakerState::HalfOpen;
+
+                Ok(Admission { breaker: self, trial: true, settled: false })
+            },
+            // Either still open, or another call is already the trial
+            _ => Err(self.refusal(&breaker))
+        }
+    }
+    fn record ( &self, succeeded: bool ) {
+        let mut breaker = self.lock();
+
+        if succeeded {
+            breaker.state = BreakerState::Closed;
+            breaker.failures = 0;
+
+            return;
+        }
+
+        breaker.failures += 1;
+        let opens = match breaker.state {
+            BreakerState::HalfOpen => {
+                tracing::warn!(provider = self.provider.as_str(), "Circuit breaker reopened; its trial call failed");
+
+                true
+            },
+            BreakerState::Closed if breaker.failures >= self.threshold => {
+                tracing::warn!(provider = self.provider.as_str(), failures = breaker.failures, "Circuit breaker opened");
+
+                true
+            },
+            _ => false
+        };
+        if opens {
+            breaker.state = BreakerState::Open;
+            breaker.reopens = Instant::now() + self.open_for;
+        }
+    }
+    // A trial call that told us nothing; the next call becomes the trial
+    fn release_trial ( &self ) {
+        let mut breaker = self.lock();
+
+        if breaker.state == BreakerState::HalfOpen {
+            breaker.state = BreakerState::Open;
+            breaker.reopens = Instant::now();
+        }
+    }
+    fn refusal ( &self, breaker: &Breaker ) -> BreakerOpen {
+        BreakerOpen {
+            provider:    self.provider,
+            retry_after: breaker.reopens.saturating_duration_since(Instant::now())
+        }
+    }
+
+    fn lock ( &self ) -> std::sync::MutexGuard<'_, Breaker> {
+        self.breaker.lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// One admitted call. If it's the trial call and never records an outcome,
+//  e.g. because its future was dropped, it hands the trial on when dropped
+//  rather than leaving the breaker half-open for good.
+struct Admission<'a> {
+    breaker: &'a CircuitBreaker,
+    trial:   bool,
+    settled: bool
+}
+impl Admission<'_> {
+    fn record ( mut self, succeeded: bool ) {
+        self.settled = true;
+        self.breaker.record(succeeded);
+    }
+}
+impl Drop for Admission<'_> {
+    fn drop ( &mut self ) {
+        if self.trial && !self.settled {
+            self.breaker.release_trial();
+        }
+    }
+}
+
+/// Exponential backoff with full jitter, so callers that failed together
+///  don't all retry together.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration
+}
+impl RetryPolicy {
+    fn delay ( &self, attempt: u32 ) -> Duration {
+        let ceiling = self.backoff.saturating_mul(2u32.saturating_pow(attempt));
+
+        ceiling.mul_f64(fastrand::f64())
+    }
+}
+
+/// Everything between an adapter and its provider: health tracking,
+///  retries of transient failures and a circuit breaker. Tuned per
+///  provider with e.g. `SNUSBASE_RETRIES`, `SNUSBASE_RETRY_BACKOFF_MS`,
+///  `SNUSBASE_BREAKER_THRESHOLD` and `SNUSBASE_BREAKER_OPEN_SECS`.
+#[derive(Debug)]
+pub struct Resilience {
+    pub health:  ProviderHealth,
+    pub breaker: CircuitBreaker,
+    pub retry:   RetryPolicy
+}
+impl Resilience {
+    pub fn from_env ( provider: Provider, default_backoff_ms: u64 ) -> Result<Self> {
+        let prefix = provider.as_str().to_uppercase();
+
+        let threshold = number_from_env(&format!("{prefix}_BREAKER_THRESHOLD"), 5)?;
+        if threshold == 0 {
+            bail!("{prefix}_BREAKER_THRESHOLD must be at least 1!");
+        }
+
+        Ok(Self {
+            health:  ProviderHealth::new(),
+            breaker: CircuitBreaker {
+                provider,
+                breaker:  Mutex::new(Breaker {
+                    state:    BreakerState::Closed,
+                    failures: 0,
+                    reopens:  Instant::now()
+                }),
+                threshold,
+                open_for: Duration::from_secs(number_from_env(&format!("{prefix}_BREAKER_OPEN_SECS"), 30)?)
+            },
+            retry:   RetryPolicy {
+                retries: number_from_env(&format!("{prefix}_RETRIES"), 2)?,
+                backoff: Duration::from_millis(number_from_env(&format!("{prefix}_RETRY_BACKOFF_MS"), default_backoff_ms)?)
+            }
+        })
+    }
+
+    /// Makes an idempotent call, retrying it while it fails transiently.
+    ///  `operation` labels its metrics.
+    pub async fn call<T, F, Fut> ( &self, operation: &'static str, attempt: F ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>
+    {
+        self.call_retrying_if(operation, || true, attempt).await
+    }
+    /// Like `call`, but only retries while `may_retry` also allows it,
+    ///  e.g. while a failed attempt hadn't yet produced anything.
+    pub async fn call_retrying_if<T, F, Fut> (
+        &self,
+        operation: &'static str,
+        may_retry: impl Fn() -> bool,
+        mut attempt: F
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>
+    {
+        let provider = self.breaker.provider;
+        let mut retries = 0;
+
+        loop {
+            let admission = match self.breaker.admit() {
+                Ok(admission) => admission,
+                Err(refusal) => {
+                    metrics().observe_upstream(provider, operation, "rejected", Duration::ZERO);
+
+                    return Err(refusal.into());
+                }
+            };
+
+            let started = Instant::now();
+            let outcome = self.health.observe(attempt()).await;
+            let transient = outcome.as_ref().err().is_some_and(is_transient);
+            let elapsed = started.elapsed();
+
+            metrics().observe_upstream(
+                provider,
+                operation,
+                if outcome.is_ok() { "ok" } else { "error" },
+                elapsed
+            );
+            match &outcome {
+                Ok(_) => tracing::info!(
+                    provider = provider.as_str(), operation, elapsed_ms = elapsed.as_millis() as u64,
+                    "Upstream call succeeded"
+                ),
+                Err(e) => tracing::warn!(
+                    provider = provider.as_str(), operation, elapsed_ms = elapsed.as_millis() as u64,
+                    error = %describe(e), "Upstream call failed"
+                )
+            }
+
+            // Only failures that are the provider's fault count against it;
+            //  others (a rejected query, a bad response) don't count either way
+            match &outcome {
+                Ok(_) => admission.record(true),
+                Err(_) if transient => admission.record(false),
+                Err(_) => drop(admission)
+            }
+
+            match outcome {
+                // Once the breaker opens, the failure itself is the better answer
+                Err(e) if transient && retries < self.retry.retries && may_retry()
+                    && self.breaker.state() == BreakerState::Closed =>
+                {
+                    tracing::warn!(
+                        provider = provider.as_str(), operation, attempt = retries + 1,
+                        error = %describe(&e), "Retrying upstream call"
+                    );
+
+                    tokio::time::sleep(self.retry.delay(retries)).await;
+                    retries += 1;
+                },
+                outcome => return outcome
+            }
+        }
+    }
+}
+
+/// Whether a failure is likely to pass if the call is retried: the
+///  provider couldn't be reached, timed out, or had a server-side error.
+pub fn is_transient ( error: &anyhow::Error ) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_connect() || e.is_timeout() || e.status()
+                .is_some_and(|status| status.is_server_error() || status.as_u16() == 429);
+        }
+
+        cause.is::<tokio::time::error::Elapsed>()
+            || cause.is::<tokio_tungstenite::tungstenite::Error>()
+            || cause.is::<std::io::Error>()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::anyhow;
+
+    fn resilience ( threshold: u32 ) -> Resilience {
+        Resilience {
+            health:  ProviderHealth::new(),
+            breaker: CircuitBreaker {
+                provider: Provider::Snusbase,
+                breaker:  Mutex::new(Breaker {
+                    state:    BreakerState::Closed,
+                    failures: 0,
+                    reopens:  Instant::now()
+                }),
+                threshold,
+                open_for: Duration::ZERO
+            },
+            retry:   RetryPolicy { retries: 0, backoff: Duration::ZERO }
+        }
+    }
+    async fn unreachable ( resilience: &Resilience ) {
+        let _ = resilience.call("test", || async {
+            Err::<(), _>(std::io::Error::other("connection reset").into())
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn a_dropped_trial_call_hands_the_trial_on () {
+        let resilience = resilience(1);
+        unreachable(&resilience).await;
+        assert_eq!(resilience.breaker.state(), BreakerState::Open);
+
+        let trial = resilience.call("test", std::future::pending::<Result<()>>);
+        assert!(tokio::time::timeout(Duration::from_millis(10), trial).await.is_err());
+        assert_eq!(resilience.breaker.state(), BreakerState::Open);
+
+        resilience.call("test", || async { Ok(()) }).await.unwrap();
+        assert_eq!(resilience.breaker.state(), BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn only_transient_failures_count () {
+        let resilience = resilience(2);
+        unreachable(&resilience).await;
+
+        // Neither a failure nor a success, so the count isn't reset
+        let _ = resilience.call("test", || async { Err::<(), _>(anyhow!("malformed response")) }).await;
+        assert_eq!(resilience.breaker.state(), BreakerState::Closed);
+
+        unreachable(&resilience).await;
+        assert_eq!(resilience.breaker.state(), BreakerState::Open);
+    }
+}
@@ -1,94 +1,460 @@
-use tungstenite::connect;
-use anyhow::{Result, Context, anyhow};
-use serde::{Serialize, Deserialize};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SherlockResponse {
-    pub username: String,
-    pub sites: Vec<String>
-}
-
-pub struct Sherlock { }
-impl Sherlock {
-    pub fn new () -> Result<Self> {
-        // Ensure the required environment variables are set
-        let _ = std::env::var("SHERLOCK_WS_URL")
-            .context("SHERLOCK_WS_URL not set!")?;
-
-        // Verify you can connect to Sherlock
-        let _ = connect(&std::env::var("SHERLOCK_WS_URL")
-            .context("Can't connect to Sherlock! Is the Sherlock REST API started?")?)
-            .context("Can't connect to Sherlock! Is the Sherlock REST API started?")?;
-
-        Ok(Self { })
-    }
-    pub async fn get_and_stringify_potential_profiles(
-        &self,
-        username: String, 
-        allow_all: bool
-    ) -> Result<SherlockResponse> {
-        let mut ret = Vec::new();
-        
-        // If the username is bad, let the user know.
-        if !Self::is_valid_sherlock_username(&username, allow_all) {
-            return Err(anyhow!("Username would produce very poor results!"));
-        }
-        
-        println!("Querying Sherlock for {username}");
-
-        let sherlock_ws_url = std::env::var("SHERLOCK_WS_URL")
-            .expect("SHERLOCK_WS_URL not set!");
-        let (
-            mut socket,
-            response
-        ) = connect(&sherlock_ws_url)
-            .context("Can't connect to Sherlock! Is the Sherlock REST API started?")?;
-        let status = response.status();
-
-        println!("Connected to Sherlock API!");
-        println!("Response HTTP code: {status}");
-
-        socket.send(tungstenite::protocol::Message::Text(format!("{username}")))
-            .context("Failed to send message to Sherlock API!")?;
-
-        loop {
-            let message = socket.read()
-                .context("Failed to read message from Sherlock API!")?;
-
-            if let tungstenite::protocol::Message::Text(text) = message {
-                if text.contains("http") || text.contains("https") {
-                    println!("Found site for {username}: {text}");
-                    
-                    ret.push(text);
-                }
-            } else {
-                break;
-            }
-        }
-
-        let ret = SherlockResponse {
-            username,
-            sites: ret
-        };
-    
-        Ok(ret)
-    }
-    fn is_valid_sherlock_username ( 
-        username: &str,
-        allow_all: bool 
-    ) -> bool {
-        let invalid_characters: [char; 5] = [' ', '.', '-', '_', '#'];
-        
-        let has_no_invalid_char: bool = !invalid_characters
-            .iter()
-            .any(|&ch| username.contains(ch));
-        let has_alpha_first: bool = username
-            .chars()
-            .next().unwrap_or(' ')
-            .is_alphabetic();
-        let within_length: bool = username.chars().count() < 20;
-    
-        // If the username is bad, let the user know.
-        allow_all || ( has_no_invalid_char && has_alpha_first && within_length )
-    }
-}
\ No newline at end of file
+use crate::apis::limits::concurrency_from_env;
+use crate::apis::http::timeout_from_env;
+use crate::apis::resilience::Resilience;
+use crate::apis::health::describe;
+use crate::helper::types::{ PII, Provider };
+use crate::helper::fields::FieldSchema;
+use crate::helper::findings::{ Finding, Identity, Normalize };
+use crate::helper::usernames::{ UsernamePolicy, UsernameRejection, ValidationMode };
+use crate::helper::request_id;
+
+use std::collections::HashMap;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant };
+
+use anyhow::{Result, Context, anyhow};
+use futures_util::{ SinkExt, StreamExt };
+use serde::{Serialize, Deserialize};
+use tokio::net::TcpStream;
+use tokio::sync::{ Semaphore, mpsc };
+use tokio_tungstenite::{
+    connect_async, MaybeTlsStream, WebSocketStream,
+    tungstenite::{ Message, client::IntoClientRequest, http::HeaderValue }
+};
+
+/// Whether a site has an account under the searched username.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SiteStatus {
+    Claimed,
+    Available,
+    /// Sherlock couldn't tell, e.g. the site errored or blocked the check.
+    Unknown
+}
+
+/// One site Sherlock checked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SherlockSite {
+    pub site:     String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url:      Option<String>,
+    pub status:   SiteStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_time_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error:    Option<String>
+}
+/// A result as the Sherlock service sends it when it speaks JSON.
+#[derive(Debug, Deserialize)]
+struct RawSherlockSite {
+    #[serde(alias = "site_name")]
+    site:   String,
+    #[serde(default, alias = "site_url_user")]
+    url:    Option<String>,
+    status: String,
+    /// Seconds, as Sherlock measures it.
+    #[serde(default)]
+    query_time: Option<f64>,
+    #[serde(default, alias = "context")]
+    error:  Option<String>
+}
+impl SherlockSite {
+    /// Parses one message from the Sherlock service, which is either a
+    ///  JSON result or a line of its console output, e.g.
+    ///  `[+] GitHub: https://github.com/user`, `[+] [120ms] GitHub: ...`
+    ///  or `[-] GitHub: Not Found!`. Progress lines (`[*] ...`) aren't
+    ///  results and give `None`.
+    pub fn parse ( message: &str ) -> Option<Self> {
+        let message = message.trim();
+
+        // Anything else with a URL in it is taken as a found profile, as
+        //  before Sherlock's output was parsed
+        Self::parse_result(message)
+            .or_else(|| Self::parse_url(message))
+    }
+    fn parse_url ( message: &str ) -> Option<Self> {
+        let url = &message[message.find("http")?..];
+        let url = url.split(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '<' | '>'))
+            .next()?
+            .trim_end_matches([',', '.', ';', ')', ']']);
+        let site = reqwest::Url::parse(url).ok()
+            .and_then(|url| url.host_str().map(str::to_owned))
+            .unwrap_or_else(|| url.to_owned());
+
+        Some(Self {
+            site,
+            url:  Some(url.to_owned()),
+            status: SiteStatus::Claimed,
+            category: None,
+            response_time_ms: None,
+            error: None
+        })
+    }
+    fn parse_result ( message: &str ) -> Option<Self> {
+        if message.starts_with('{') {
+            let raw: RawSherlockSite = serde_json::from_str(message).ok()?;
+            let status = match raw.status.to_lowercase().as_str() {
+                "claimed"   => SiteStatus::Claimed,
+                "available" => SiteStatus::Available,
+                _           => SiteStatus::Unknown
+            };
+
+            return Some(Self {
+                site: raw.site,
+                url:  raw.url,
+                status,
+                category: None,
+                response_time_ms: raw.query_time.map(|secs| (secs * 1000.0).round() as u64),
+                error: raw.error.filter(|_| status == SiteStatus::Unknown)
+            });
+        }
+
+        let (claimed, rest) = match message.strip_prefix("[+]") {
+            Some(rest) => (true, rest),
+            None => (false, message.strip_prefix("[-]")?)
+        };
+        let mut rest = rest.trim();
+
+        // Verbose output puts the response time before the site
+        let mut response_time_ms = None;
+        if let Some(timed) = rest.strip_prefix('[') {
+            if let Some((time, after)) = timed.split_once(']') {
+                response_time_ms = time.trim().trim_end_matches("ms").trim().parse().ok();
+                rest = after.trim();
+            }
+        }
+
+        let (site, detail) = rest.split_once(": ")?;
+        let (site, detail) = (site.trim().to_owned(), detail.trim());
+
+        Some(match claimed {
+            true => Self {
+                site,
+                url: Some(detail.to_owned()),
+                status: SiteStatus::Claimed,
+                category: None,
+                response_time_ms,
+                error: None
+            },
+            false if detail.to_lowercase().contains("not found") => Self {
+                site,
+                url: None,
+                status: SiteStatus::Available,
+                category: None,
+                response_time_ms,
+                error: None
+            },
+            false => Self {
+                site,
+                url: None,
+                status: SiteStatus::Unknown,
+                category: None,
+                response_time_ms,
+                error: Some(detail.to_owned())
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SherlockResponse {
+    pub username: String,
+    /// URLs of the profiles Sherlock found, i.e. the claimed `results`.
+    pub sites:    Vec<String>,
+    pub results:  Vec<SherlockSite>
+}
+impl SherlockResponse {
+    fn new ( username: String, results: Vec<SherlockSite> ) -> Self {
+        let sites = results.iter()
+            .filter(|entry| entry.status == SiteStatus::Claimed)
+            .filter_map(|entry| entry.url.clone())
+            .collect();
+
+        Self { username, sites, results }
+    }
+    /// Keeps only the results `filter` lets through.
+    pub fn retain ( self, filter: &SherlockFilter ) -> Self {
+        let results = self.results.into_iter()
+            .filter(|entry| filter.matches(entry))
+            .collect();
+
+        Self::new(self.username, results)
+    }
+}
+impl Normalize for SherlockResponse {
+    fn normalize ( &self, _schema: &FieldSchema ) -> Vec<Finding> {
+        self.results
+            .iter()
+            .filter(|entry| entry.status == SiteStatus::Claimed)
+            .map(|entry| Finding {
+                provider: Provider::Sherlock,
+                source:   Some(entry.site.clone()),
+                url:      entry.url.clone(),
+                observed: None,
+                identity: Identity {
+                    username: Some(self.username.clone()),
+                    ..Identity::default()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Narrows Sherlock results to some sites and/or categories, each given
+///  as a comma-separated list, e.g. `?sites=GitHub,GitLab&categories=social`.
+///  Names match case-insensitively; an empty filter lets everything through.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SherlockFilter {
+    #[serde(default)]
+    pub sites:      Option<String>,
+    #[serde(default)]
+    pub categories: Option<String>
+}
+impl SherlockFilter {
+    pub fn matches ( &self, entry: &SherlockSite ) -> bool {
+        let listed = |list: &Option<String>, value: Option<&str>| match list {
+            None => true,
+            Some(list) => value.is_some_and(|value| list.split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(value)))
+        };
+
+        listed(&self.sites, Some(&entry.site))
+            && listed(&self.categories, entry.category.as_deref())
+    }
+}
+
+/// Built-in site categories; `SHERLOCK_CATEGORIES_PATH` can point at a
+///  JSON object of more (or overriding) ones, e.g. `{"code": ["Gitea"]}`.
+const DEFAULT_CATEGORIES: &[(&str, &[&str])] = &[
+    ("social",   &["Twitter", "X", "Facebook", "Instagram", "Reddit", "TikTok", "Mastodon", "Threads", "Bluesky", "Pinterest", "Tumblr", "VK"]),
+    ("code",     &["GitHub", "GitLab", "Bitbucket", "Codeberg", "Replit", "Docker Hub", "npm", "PyPI", "HackerNews", "Kaggle"]),
+    ("gaming",   &["Steam", "Twitch", "Roblox", "Chess", "Xbox Gamertag", "Lichess", "osu!", "Minecraft"]),
+    ("media",    &["YouTube", "SoundCloud", "Spotify", "Vimeo", "Flickr", "Medium", "Patreon", "DeviantART"]),
+    ("forum",    &["Quora", "StackOverflow", "Disqus", "Discord"]),
+    ("dating",   &["Tinder", "Badoo", "OkCupid"]),
+    ("commerce", &["eBay", "Etsy", "Fiverr", "Freelancer", "Venmo", "CashApp"])
+];
+
+pub struct Sherlock {
+    url:        String,
+    permits:    Semaphore,
+    /// Lowercased site name to category.
+    categories: HashMap<String, String>,
+    usernames:  UsernamePolicy,
+
+    /// To open the WebSocket, per attempt.
+    connect_timeout: Duration,
+    /// Between messages; Sherlock reports each site as it's checked, so
+    ///  a long silence means it's hung.
+    read_timeout:    Duration,
+    /// For a whole lookup, retries included.
+    timeout:         Duration,
+
+    /// The last readiness probe, reused for `SHERLOCK_PROBE_TTL_SECS`.
+    probed:    tokio::sync::Mutex<Option<(Instant, Result<(), String>)>>,
+    probe_ttl: Duration,
+
+    pub resilience: Resilience
+}
+impl Sherlock {
+    /// PII types the username search accepts.
+    pub const LOOKUP_TYPES: &'static [PII] = &[ PII::Username ];
+
+    /// Doesn't connect; see `probe`, so that the API can start (and serve
+    ///  everything else) while Sherlock is down.
+    pub fn new () -> Result<Self> {
+        Ok(Self {
+            url:        std::env::var("SHERLOCK_WS_URL")
+                .context("SHERLOCK_WS_URL not set!")?,
+            permits:    concurrency_from_env("SHERLOCK_MAX_CONCURRENCY", 2)?,
+            categories: Self::categories_from_env()?,
+            usernames:  UsernamePolicy::from_env()?,
+
+            connect_timeout: timeout_from_env("SHERLOCK_CONNECT_TIMEOUT_SECS", 10)?,
+            read_timeout:    timeout_from_env("SHERLOCK_READ_TIMEOUT_SECS", 60)?,
+            timeout:         timeout_from_env("SHERLOCK_TIMEOUT_SECS", 10 * 60)?,
+
+            probed:    tokio::sync::Mutex::new(None),
+            probe_ttl: timeout_from_env("SHERLOCK_PROBE_TTL_SECS", 30)?,
+
+            resilience: Resilience::from_env(Provider::Sherlock, 500)?
+        })
+    }
+    /// Checks that Sherlock accepts connections. The result is reused for
+    ///  a while, so frequent readiness probes don't each open a socket,
+    ///  and it's kept out of `resilience.health`, which tracks lookups.
+    pub async fn probe ( &self ) -> Result<()> {
+        // Held while connecting, so concurrent probes share one check
+        let mut probed = self.probed.lock().await;
+
+        let outcome = match probed.as_ref() {
+            Some((at, outcome)) if at.elapsed() < self.probe_ttl => outcome.clone(),
+            _ => {
+                let outcome = async {
+                    let mut socket = self.connect().await?;
+                    let _ = socket.close(None).await;
+
+                    Ok(())
+                }.await.map_err(|e: anyhow::Error| describe(&e));
+
+                *probed = Some((Instant::now(), outcome.clone()));
+                outcome
+            }
+        };
+
+        outcome.map_err(|e| anyhow!(e))
+    }
+    fn categories_from_env () -> Result<HashMap<String, String>> {
+        let mut categories: HashMap<String, String> = DEFAULT_CATEGORIES.iter()
+            .flat_map(|(category, sites)| sites.iter()
+                .map(move |site| (site.to_lowercase(), category.to_string())))
+            .collect();
+
+        if let Ok(path) = std::env::var("SHERLOCK_CATEGORIES_PATH") {
+            let contents = std::fs::read_to_string(&path)
+                .context(format!("Failed to read Sherlock categories at `{path}`!"))?;
+            let extra: HashMap<String, Vec<String>> = serde_json::from_str(&contents)
+                .context(format!("Sherlock categories at `{path}` are not valid!"))?;
+
+            for (category, sites) in extra {
+                for site in sites {
+                    categories.insert(site.to_lowercase(), category.to_lowercase());
+                }
+            }
+        }
+
+        Ok(categories)
+    }
+    /// Checks a username against the deployment's rules before it's
+    ///  searched for (or served from the cache).
+    pub fn check_username (
+        &self,
+        username: &str,
+        mode: Option<ValidationMode>
+    ) -> Result<(), UsernameRejection> {
+        self.usernames.check(username, mode)
+    }
+    pub async fn get_and_stringify_potential_profiles(
+        &self,
+        username: String
+    ) -> Result<SherlockResponse> {
+        let (found, _) = mpsc::unbounded_channel();
+
+        self.report_potential_profiles(username, found).await
+    }
+    /// Like `get_and_stringify_potential_profiles`, but also sends each
+    ///  result down `found` as soon as Sherlock reports it.
+    pub async fn report_potential_profiles(
+        &self,
+        username: String,
+        found: mpsc::UnboundedSender<SherlockSite>
+    ) -> Result<SherlockResponse> {
+        let _permit = self.permits.acquire().await
+            .context("Sherlock concurrency limiter was closed!")?;
+
+        tracing::info!(username = %username, "Querying Sherlock");
+
+        // A search that failed partway already reported some sites, and
+        //  retrying it would report them twice
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        let reported = AtomicBool::new(false);
+        let (username_ref, found, reported) = (&username, &found, &reported);
+
+        let sites = self.resilience.call_retrying_if(
+            "profiles",
+            || !reported.load(Ordering::Relaxed) && tokio::time::Instant::now() < deadline,
+            || async move {
+                let mut ret = Vec::new();
+                tokio::time::timeout_at(deadline, self.search(username_ref, found, reported, &mut ret)).await
+                    .context("Sherlock lookup took too long!")??;
+
+                Ok(ret)
+            }
+        ).await?;
+
+        Ok(SherlockResponse::new(username, sites))
+    }
+    async fn search (
+        &self,
+        username: &str,
+        found: &mpsc::UnboundedSender<SherlockSite>,
+        reported: &AtomicBool,
+        ret: &mut Vec<SherlockSite>
+    ) -> Result<()> {
+        let mut socket = self.connect().await?;
+
+        socket.send(Message::Text(username.to_owned())).await
+            .context("Failed to send message to Sherlock API!")?;
+
+        loop {
+            let message = tokio::time::timeout(self.read_timeout, socket.next()).await
+                .context("Sherlock stopped responding!")?;
+            let Some(message) = message else {
+                break;
+            };
+
+            match message.context("Failed to read message from Sherlock API!")? {
+                Message::Text(text) => {
+                    if let Some(mut entry) = SherlockSite::parse(&text) {
+                        if entry.status == SiteStatus::Claimed {
+                            tracing::debug!(site = %entry.site, url = entry.url.as_deref(), "Sherlock found a profile");
+                        }
+                        entry.category = self.categories.get(&entry.site.to_lowercase()).cloned();
+
+                        // Nobody listening is fine; the full list is returned anyway
+                        let _ = found.send(entry.clone());
+                        reported.store(true, Ordering::Relaxed);
+
+                        ret.push(entry);
+                    }
+                },
+                Message::Ping(_) | Message::Pong(_) => continue,
+                _ => break
+            }
+        }
+
+        Ok(())
+    }
+    async fn connect ( &self ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+        let mut request = self.url.as_str().into_client_request()
+            .context("SHERLOCK_WS_URL is not a valid WebSocket URL!")?;
+        if let Some(id) = request_id::current().and_then(|id| HeaderValue::from_str(&id).ok()) {
+            request.headers_mut().insert("X-Request-Id", id);
+        }
+
+        let (socket, response) = tokio::time::timeout(self.connect_timeout, connect_async(request)).await
+            .context("Timed out connecting to Sherlock!")?
+            .context("Can't connect to Sherlock! Is the Sherlock REST API started?")?;
+
+        tracing::debug!(status = response.status().as_u16(), "Connected to Sherlock");
+
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_results_and_falls_back_to_any_url () {
+        let site = SherlockSite::parse("[+] [120ms] GitHub: https://github.com/alice").unwrap();
+        assert_eq!((site.site.as_str(), site.status, site.response_time_ms), ("GitHub", SiteStatus::Claimed, Some(120)));
+
+        let site = SherlockSite::parse("[-] GitLab: Not Found!").unwrap();
+        assert_eq!(site.status, SiteStatus::Available);
+
+        let site = SherlockSite::parse("https://github.com/alice").unwrap();
+        assert_eq!((site.site.as_str(), site.url.as_deref()), ("github.com", Some("https://github.com/alice")));
+
+        // Formats we don't recognize still count if they carry a URL
+        for frame in [ "Found: https://x.com/alice.", "{\"profile\": \"https://x.com/alice\"", "[!] https://x.com/alice (maybe)" ] {
+            let site = SherlockSite::parse(frame).unwrap();
+            assert_eq!((site.site.as_str(), site.status), ("x.com", SiteStatus::Claimed), "{frame}");
+        }
+
+        assert!(SherlockSite::parse("[*] Checking username alice on:").is_none());
+    }
+}
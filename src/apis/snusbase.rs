@@ -1,342 +1,528 @@
-use std::collections::HashMap;
-
-use anyhow::{ Result, Context, bail };
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SnusbaseDBResponse {
-    pub took: u32,
-    pub size: u32,
-    pub results: HashMap<String, Vec<HashMap<String, Value>>>
-}
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SnusbaseHashLookupResponse {
-    pub took: u32,
-    pub size: u32,
-    pub results: HashMap<String, Vec<Value>>
-}
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SnusbaseIPResponse {
-    pub took: i32,
-    pub size: i32,
-    pub results: HashMap<String, HashMap<String, Value>>
-}
-impl SnusbaseDBResponse {
-    pub fn _dumps ( &self ) -> Vec<String> {
-        self.results
-            .keys()
-            .map(|key| key.to_string())
-            .collect()
-    }
-    pub fn _usernames ( &self ) -> Vec<String> {
-        let mut usernames = Vec::new();
-
-        for (_dump, content) in &self.results {
-            for entry in content {
-                if let Some(username) = entry.get("username") {
-                    usernames.push(username.to_string());
-                }
-            }
-        }
-
-        usernames    
-    }
-    pub fn _emails ( &self ) -> Vec<String> {
-        let mut emails = Vec::new();
-
-        for (_dump, content) in &self.results {
-            for entry in content {
-                if let Some(email) = entry.get("email") {
-                    emails.push(email.to_string());
-                }
-            }
-        }
-
-        emails
-    }
-    pub fn _passwords ( &self ) -> Vec<String> {
-        let mut passwords = Vec::new();
-
-        for (_dump, content) in &self.results {
-            for entry in content {
-                if let Some(password) = entry.get("password") {
-                    passwords.push(password.to_string());
-                }
-            }
-        }
-
-        passwords
-    }
-    pub fn _names ( &self ) -> Vec<String> {
-        let mut names = Vec::new();
-
-        for (_dump, content) in &self.results {
-            for entry in content {
-                if let Some(name) = entry.get("name") {
-                    names.push(name.to_string());
-                }
-            }
-        }
-
-        names
-    }
-    pub fn _last_ips ( &self ) -> Vec<String> {
-        let mut last_ips = Vec::new();
-
-        for (_dump, content) in &self.results {
-            for entry in content {
-                if let Some(last_ip) = entry.get("last_ip") {
-                    last_ips.push(last_ip.to_string());
-                }
-            }
-        }
-
-        last_ips
-    }
-    pub fn _addresses ( &self ) -> Vec<String> {
-        let mut addresses = Vec::new();
-
-        for (_dump, content) in &self.results {
-            for entry in content {
-                if let Some(address) = entry.get("address") {
-                    addresses.push(address.to_string());
-
-                    continue;
-                }
-                if let Some(zip) = entry.get("zip") {
-                    addresses.push(zip.to_string());
-
-                    continue;
-                }
-            }
-        }
-
-        addresses
-    }
-    pub fn _companies ( &self ) -> Vec<String> {
-        let mut companies = Vec::new();
-
-        for (_dump, content) in &self.results {
-            for entry in content {
-                if let Some(company) = entry.get("company") {
-                    companies.push(company.to_string());
-                }
-            }
-        }
-
-        companies
-    }
-    pub fn _other ( &self ) -> Vec<String> {
-        let mut other = Vec::new();
-
-        for (_dump, content) in &self.results {
-            for entry in content {
-                for (key, value) in entry {
-                    if key == "username" || key == "email" || key == "password" || key == "name" || key == "last_ip" || key == "address" || key == "zip" || key == "company" {
-                        continue;
-                    }
-
-                    other.push(format!("{key}: {value}"));
-                }
-            }
-        }
-
-        other
-    }
-}
-#[derive(Debug)]
-pub struct Snusbase {
-    api_key: String,
-}
-impl Snusbase {
-    pub fn new() -> Result<Self> {
-        Ok(Self { 
-            api_key: std::env::var("SNUSBASE_API_KEY")
-                .context("Missing 'SNUSBASE_API_KEY' environment variable!")? 
-        })
-    }
-    pub async fn whois_ip_query (
-        &self,
-        ips: Vec<String>
-    ) -> Result<SnusbaseIPResponse> {
-        if ips.len() == 0 {
-            bail!("No IPs to query!");
-        }
-
-        // Build a proxied `ureq` client
-        let proxy = ureq::Proxy::new(&std::env::var("PROXY_LINK")
-            .context("PROXY_LINK not set!")?)?;
-        let agent = ureq::AgentBuilder::new()
-            .proxy(proxy)
-            .build();
-        
-        // Query Snusbase
-        let resp_object = agent.post("https://api-experimental.snusbase.com/tools/ip-whois")
-            .set("Auth", &self.api_key )
-            .set("Content-Type", "application/json")
-            .send_json(ureq::json!({
-                "terms": ips
-            }))
-            .map_err(|e| anyhow::anyhow!("Failed to query IP geolocation backend! {:?}", e))?;
-
-        // Debug print response
-        let resp_as_string = resp_object.into_string()
-            .context("Failed to convert response to string! It was probably too big.")?;
-        
-        // Deserialize response with serde_json
-        let deserialized_resp: SnusbaseIPResponse = serde_json::from_str(&resp_as_string)
-            .context("Failed to deserialize response!")?;
-        
-        Ok(deserialized_resp)
-    }
-    pub async fn database_query ( 
-        &self,
-        terms: Vec<String>,
-        types: Vec<String>,
-        wildcard: bool
-    ) -> Result<SnusbaseDBResponse> {
-        // Build a proxied `ureq` client
-        let proxy = ureq::Proxy::new(&std::env::var("PROXY_LINK")
-            .context("PROXY_LINK not set!")?)?;
-        let agent = ureq::AgentBuilder::new()
-            .proxy(proxy)
-            .build();
-
-        // Query Snusbase
-        let resp_object = agent.post("https://api-experimental.snusbase.com/data/search")
-            .set("Auth", &self.api_key )
-            .set("Content-Type", "application/json")
-            .send_json(ureq::json!({
-                "terms": terms,
-                "types": types,
-                "wildcard": wildcard
-            }))
-            .map_err(|e| anyhow::anyhow!("Failed to query database query backend! {:?}", e))?;
-
-        // Debug print response
-        let resp_as_string = resp_object.into_string()
-            .context("Failed to convert response to string!")?;
-        
-        // Deserialize response with serde_json
-        let deserialized_resp: SnusbaseDBResponse = serde_json::from_str(&resp_as_string)
-            .context("Failed to deserialize response!")?;
-        
-        Ok(deserialized_resp)
-    }
-    pub async fn hash_lookup_query ( 
-        &self,
-        terms: Vec<String>,
-        types: Vec<String>,
-        wildcard: bool
-    ) -> Result<SnusbaseHashLookupResponse> {
-        // Build a proxied `ureq` client
-        let proxy = ureq::Proxy::new(&std::env::var("PROXY_LINK")
-            .context("PROXY_LINK not set!")?)?;
-        let agent = ureq::AgentBuilder::new()
-            .proxy(proxy)
-            .build();
-
-        // Query Snusbase
-        let resp_object = agent.post("https://api-experimental.snusbase.com/tools/hash-lookup")
-            .set("Auth", &self.api_key )
-            .set("Content-Type", "application/json")
-            .send_json(ureq::json!({
-                "terms": terms,
-                "types": types,
-                "wildcard": wildcard
-            }))
-            .map_err(|e| anyhow::anyhow!("Failed to query database query backend! {:?}", e))?;
-
-        // Debug print response
-        let resp_as_string = resp_object.into_string()
-            .context("Failed to convert response to string!")?;
-        
-        // Deserialize response with serde_json
-        let deserialized_resp: SnusbaseHashLookupResponse = serde_json::from_str(&resp_as_string)
-            .context("Failed to deserialize response!")?;
-        
-        Ok(deserialized_resp)
-    }
-    pub async fn get_by_email (
-        &self,
-        email: String
-    ) -> Result<SnusbaseDBResponse> {
-        self.database_query(
-            vec!(email),
-            vec!(String::from("email")),
-            false
-        ).await
-    }
-    pub async fn get_by_username (
-        &self,
-        username: String
-    ) -> Result<SnusbaseDBResponse> {
-        self.database_query(
-            vec!(username),
-            vec!(String::from("username")),
-            false
-        ).await
-    }
-    pub async fn get_by_last_ip (
-        &self,
-        last_ip: String
-    ) -> Result<SnusbaseDBResponse> {
-        self.database_query(
-            vec!(last_ip),
-            vec!(String::from("lastip")),
-            false
-        ).await
-    }
-    pub async fn get_by_password (
-        &self,
-        password: String
-    ) -> Result<SnusbaseDBResponse> {
-        self.database_query(
-            vec!(password),
-            vec!(String::from("password")),
-            false
-        ).await
-    }
-    pub async fn get_by_name (
-        &self,
-        name: String
-    ) -> Result<SnusbaseDBResponse> {
-        self.database_query(
-            vec!(name),
-            vec!(String::from("name")),
-            false
-        ).await
-    }
-    pub async fn get_by_hash (
-        &self,
-        hash: String
-    ) -> Result<SnusbaseDBResponse> {
-        self.database_query(
-            vec!(hash),
-            vec!(String::from("hash")),
-            false
-        ).await
-    }
-    pub async fn rehash (
-        &self,
-        password: String
-    ) -> Result<SnusbaseHashLookupResponse> {
-        self.hash_lookup_query(
-            vec!(password),
-            vec!(String::from("password")),
-            false
-        ).await
-    }
-    pub async fn dehash (
-        &self,
-        hash: String
-    ) -> Result<SnusbaseHashLookupResponse> {
-        self.hash_lookup_query(
-            vec!(hash),
-            vec!(String::from("hash")),
-            false
-        ).await
-    }
-}
\ No newline at end of file
+use crate::apis::http::{ HttpClients, timeout_from_env };
+use crate::apis::limits::concurrency_from_env;
+use crate::apis::resilience::Resilience;
+use crate::helper::types::{ PII, Provider };
+use crate::helper::fields::{ FieldSchema, FieldCategory };
+use crate::helper::findings::{ Finding, Identity, Normalize };
+use crate::helper::request_id;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{ Result, Context, bail };
+use serde::{ Deserialize, Serialize, de::DeserializeOwned };
+use serde_json::{ json, Value };
+use tokio::sync::Semaphore;
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnusbaseDBResponse {
+    pub took: u32,
+    pub size: u32,
+    pub results: HashMap<String, Vec<HashMap<String, Value>>>
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnusbaseHashLookupResponse {
+    pub took: u32,
+    pub size: u32,
+    pub results: HashMap<String, Vec<Value>>
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnusbaseIPResponse {
+    pub took: i32,
+    pub size: i32,
+    pub results: HashMap<String, HashMap<String, Value>>
+}
+impl SnusbaseDBResponse {
+    pub fn _dumps ( &self ) -> Vec<String> {
+        self.results
+            .keys()
+            .map(|key| key.to_string())
+            .collect()
+    }
+    /// Every value of a field `schema` classifies as `category`.
+    pub fn _values ( &self, schema: &FieldSchema, category: FieldCategory ) -> Vec<String> {
+        let mut values = Vec::new();
+
+        for content in self.results.values() {
+            for entry in content {
+                for (key, value) in entry {
+                    if schema.classify(key) == category {
+                        values.push(match value {
+                            Value::String(value) => value.clone(),
+                            value => value.to_string()
+                        });
+                    }
+                }
+            }
+        }
+
+        values
+    }
+    pub fn _usernames ( &self, schema: &FieldSchema ) -> Vec<String> {
+        self._values(schema, FieldCategory::Username)
+    }
+    pub fn _emails ( &self, schema: &FieldSchema ) -> Vec<String> {
+        self._values(schema, FieldCategory::Email)
+    }
+    pub fn _passwords ( &self, schema: &FieldSchema ) -> Vec<String> {
+        self._values(schema, FieldCategory::Password)
+    }
+    pub fn _names ( &self, schema: &FieldSchema ) -> Vec<String> {
+        self._values(schema, FieldCategory::Name)
+    }
+    pub fn _last_ips ( &self, schema: &FieldSchema ) -> Vec<String> {
+        self._values(schema, FieldCategory::Ip)
+    }
+    pub fn _addresses ( &self, schema: &FieldSchema ) -> Vec<String> {
+        self._values(schema, FieldCategory::Address)
+    }
+    pub fn _companies ( &self, schema: &FieldSchema ) -> Vec<String> {
+        self._values(schema, FieldCategory::Company)
+    }
+    pub fn _other ( &self, schema: &FieldSchema ) -> Vec<String> {
+        let mut other = Vec::new();
+
+        for content in self.results.values() {
+            for entry in content {
+                for (key, value) in entry {
+                    if schema.classify(key) == FieldCategory::Other {
+                        other.push(format!("{key}: {value}"));
+                    }
+                }
+            }
+        }
+
+        other
+    }
+}
+impl Normalize for SnusbaseDBResponse {
+    fn normalize ( &self, schema: &FieldSchema ) -> Vec<Finding> {
+        let mut findings = Vec::new();
+
+        for (dump, entries) in &self.results {
+            for entry in entries {
+                let (identity, observed) = Identity::from_fields(schema, entry);
+
+                findings.push(Finding {
+                    provider: Provider::Snusbase,
+                    source:   Some(dump.clone()),
+                    url:      None,
+                    observed,
+                    identity
+                });
+            }
+        }
+
+        findings
+    }
+}
+impl Normalize for SnusbaseHashLookupResponse {
+    fn normalize ( &self, schema: &FieldSchema ) -> Vec<Finding> {
+        let mut findings = Vec::new();
+
+        for (dump, entries) in &self.results {
+            for entry in entries.iter().filter_map(Value::as_object) {
+                let (identity, observed) = Identity::from_fields(schema, entry);
+
+                findings.push(Finding {
+                    provider: Provider::Snusbase,
+                    source:   Some(dump.clone()),
+                    url:      None,
+                    observed,
+                    identity
+                });
+            }
+        }
+
+        findings
+    }
+}
+impl Normalize for SnusbaseIPResponse {
+    fn normalize ( &self, schema: &FieldSchema ) -> Vec<Finding> {
+        self.results
+            .iter()
+            .map(|(ip, content)| {
+                let (mut identity, observed) = Identity::from_fields(schema, content);
+
+                // The looked-up address is the key, not one of the fields
+                identity.ip.get_or_insert_with(|| ip.clone());
+
+                Finding {
+                    provider: Provider::Snusbase,
+                    source:   None,
+                    url:      None,
+                    observed,
+                    identity
+                }
+            })
+            .collect()
+    }
+}
+#[derive(Debug)]
+pub struct Snusbase {
+    api_key:  String,
+    base_url: String,
+    client:  reqwest::Client,
+    timeout: Duration,
+    permits: Semaphore,
+
+    wildcard_min_literals: usize,
+
+    pub resilience: Resilience
+}
+impl Snusbase {
+    pub fn new( clients: &HttpClients ) -> Result<Self> {
+        Ok(Self { 
+            api_key: std::env::var("SNUSBASE_API_KEY")
+                .context("Missing 'SNUSBASE_API_KEY' environment variable!")?,
+            base_url: std::env::var("SNUSBASE_URL")
+                .unwrap_or_else(|_| String::from("https://api-experimental.snusbase.com")),
+            client:  clients.proxied.clone(),
+            timeout: timeout_from_env("SNUSBASE_TIMEOUT_SECS", 30)?,
+            permits: concurrency_from_env("SNUSBASE_MAX_CONCURRENCY", 8)?,
+
+            wildcard_min_literals: match std::env::var("SNUSBASE_WILDCARD_MIN_LITERALS") {
+                Ok(value) => value.parse::<usize>()
+                    .context("SNUSBASE_WILDCARD_MIN_LITERALS must be a whole number!")?,
+                Err(_) => 4
+            },
+
+            resilience: Resilience::from_env(Provider::Snusbase, 250)?
+        })
+    }
+
+    /// Rejects wildcard patterns so broad they would sweep most of a
+    ///  table, i.e. ones with too few literal (non-`%`, non-`_`) characters.
+    pub fn validate_wildcard ( &self, pattern: &str ) -> Result<()> {
+        let literals = pattern.chars()
+            .filter(|ch| !matches!(ch, '%' | '_') && !ch.is_whitespace())
+            .count();
+
+        if literals < self.wildcard_min_literals {
+            bail!(
+                "Wildcard pattern must contain at least {} literal characters besides `%` and `_`!",
+                self.wildcard_min_literals
+            );
+        }
+
+        Ok(())
+    }
+    async fn post<T: DeserializeOwned> (
+        &self,
+        operation: &'static str,
+        path:      &str,
+        body:      Value
+    ) -> Result<T> {
+        let (url, body) = (&format!("{}{path}", self.base_url), &body);
+
+        // Taken per attempt, so a call waiting out a retry's backoff doesn't hold one
+        self.resilience.call(operation, || async move {
+            let _permit = self.permits.acquire().await
+                .context("Snusbase concurrency limiter was closed!")?;
+
+            let resp_object = request_id::forward(self.client.post(url))
+                .header("Auth", &self.api_key)
+                .timeout(self.timeout)
+                .json(&body)
+                .send().await
+                .context("Failed to send the request!")?
+                .error_for_status()
+                .context("Snusbase returned an error status!")?;
+
+            let resp_as_string = resp_object.text().await
+                .context("Failed to read response body!")?;
+
+            // Deserialize response with serde_json
+            serde_json::from_str(&resp_as_string)
+                .context("Failed to deserialize response!")
+        }).await
+    }
+    pub async fn whois_ip_query (
+        &self,
+        ips: Vec<String>
+    ) -> Result<SnusbaseIPResponse> {
+        if ips.is_empty() {
+            bail!("No IPs to query!");
+        }
+
+        // Query Snusbase
+        self.post("ip_whois", "/tools/ip-whois", json!({
+            "terms": ips
+        })).await
+            .context("Failed to query IP geolocation backend!")
+    }
+    pub async fn database_query ( 
+        &self,
+        terms: Vec<String>,
+        types: Vec<String>,
+        wildcard: bool
+    ) -> Result<SnusbaseDBResponse> {
+        // Query Snusbase
+        self.post("search", "/data/search", json!({
+            "terms": terms,
+            "types": types,
+            "wildcard": wildcard
+        })).await
+            .context("Failed to query database query backend!")
+    }
+    pub async fn hash_lookup_query ( 
+        &self,
+        terms: Vec<String>,
+        types: Vec<String>,
+        wildcard: bool
+    ) -> Result<SnusbaseHashLookupResponse> {
+        // Query Snusbase
+        self.post("hash_lookup", "/tools/hash-lookup", json!({
+            "terms": terms,
+            "types": types,
+            "wildcard": wildcard
+        })).await
+            .context("Failed to query hash lookup backend!")
+    }
+    /// PII types the database search accepts.
+    pub const SEARCH_TYPES: &'static [PII] = &[
+        PII::Email, PII::Username, PII::Ip, PII::Password,
+        PII::Hash, PII::Name, PII::Phone, PII::Domain
+    ];
+    /// PII types the hash lookup accepts.
+    pub const HASH_LOOKUP_TYPES: &'static [PII] = &[ PII::Password, PII::Hash ];
+    /// PII types the IP geolocation lookup accepts.
+    pub const GEOLOCATION_TYPES: &'static [PII] = &[ PII::Ip ];
+
+    /// Searches the database by any of `SEARCH_TYPES`.
+    pub async fn get_by (
+        &self,
+        pii_type: &PII,
+        term: String,
+        wildcard: bool
+    ) -> Result<SnusbaseDBResponse> {
+        match pii_type {
+            PII::Email    => self.get_by_email(term, wildcard).await,
+            PII::Username => self.get_by_username(term, wildcard).await,
+            PII::Ip       => self.get_by_last_ip(term, wildcard).await,
+            PII::Password => self.get_by_password(term, wildcard).await,
+            PII::Hash     => self.get_by_hash(term, wildcard).await,
+            PII::Name     => self.get_by_name(term, wildcard).await,
+            PII::Phone    => self.get_by_phone(term, wildcard).await,
+            PII::Domain   => self.get_by_domain(term, wildcard).await,
+            _ => bail!("Snusbase cannot search by {pii_type:?}!")
+        }
+    }
+    pub async fn get_by_email (
+        &self,
+        email: String,
+        wildcard: bool
+    ) -> Result<SnusbaseDBResponse> {
+        self.database_query(
+            vec!(email),
+            vec!(String::from("email")),
+            wildcard
+        ).await
+    }
+    pub async fn get_by_username (
+        &self,
+        username: String,
+        wildcard: bool
+    ) -> Result<SnusbaseDBResponse> {
+        self.database_query(
+            vec!(username),
+            vec!(String::from("username")),
+            wildcard
+        ).await
+    }
+    pub async fn get_by_last_ip (
+        &self,
+        last_ip: String,
+        wildcard: bool
+    ) -> Result<SnusbaseDBResponse> {
+        self.database_query(
+            vec!(last_ip),
+            vec!(String::from("lastip")),
+            wildcard
+        ).await
+    }
+    pub async fn get_by_password (
+        &self,
+        password: String,
+        wildcard: bool
+    ) -> Result<SnusbaseDBResponse> {
+        self.database_query(
+            vec!(password),
+            vec!(String::from("password")),
+            wildcard
+        ).await
+    }
+    pub async fn get_by_name (
+        &self,
+        name: String,
+        wildcard: bool
+    ) -> Result<SnusbaseDBResponse> {
+        self.database_query(
+            vec!(name),
+            vec!(String::from("name")),
+            wildcard
+        ).await
+    }
+    pub async fn get_by_hash (
+        &self,
+        hash: String,
+        wildcard: bool
+    ) -> Result<SnusbaseDBResponse> {
+        self.database_query(
+            vec!(hash),
+            vec!(String::from("hash")),
+            wildcard
+        ).await
+    }
+    pub async fn get_by_phone (
+        &self,
+        phone: String,
+        wildcard: bool
+    ) -> Result<SnusbaseDBResponse> {
+        self.database_query(
+            vec!(phone),
+            vec!(String::from("phone")),
+            wildcard
+        ).await
+    }
+    pub async fn get_by_domain (
+        &self,
+        domain: String,
+        wildcard: bool
+    ) -> Result<SnusbaseDBResponse> {
+        self.database_query(
+            vec!(domain),
+            vec!(String::from("_domain")),
+            wildcard
+        ).await
+    }
+    pub async fn rehash (
+        &self,
+        password: String,
+        wildcard: bool
+    ) -> Result<SnusbaseHashLookupResponse> {
+        self.hash_lookup_query(
+            vec!(password),
+            vec!(String::from("password")),
+            wildcard
+        ).await
+    }
+    pub async fn dehash (
+        &self,
+        hash: String,
+        wildcard: bool
+    ) -> Result<SnusbaseHashLookupResponse> {
+        self.hash_lookup_query(
+            vec!(hash),
+            vec!(String::from("hash")),
+            wildcard
+        ).await
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    use axum::{ http::StatusCode, response::IntoResponse, routing::post, Json, Router };
+    use crate::apis::resilience::RetryPolicy;
+
+    // Fires many searches at a mock Snusbase that answers slowly, and checks
+    //  they overlap up to the concurrency limit and never beyond it.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn searches_run_in_parallel_up_to_the_limit () {
+        const LIMIT: usize = 4;
+        const SEARCHES: usize = 24;
+        const DELAY: Duration = Duration::from_millis(100);
+
+        let (in_flight, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mock = Router::new().route("/data/search", post({
+            let (in_flight, peak) = (in_flight.clone(), peak.clone());
+
+            move || async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(DELAY).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                Json(json!({ "took": 1, "size": 0, "results": {} }))
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, mock).await.unwrap() });
+
+        let snusbase = Arc::new(Snusbase {
+            api_key:  String::from("test"),
+            base_url: format!("http://{address}"),
+            client:   reqwest::Client::new(),
+            timeout:  Duration::from_secs(10),
+            permits:  Semaphore::new(LIMIT),
+            wildcard_min_literals: 4,
+            resilience: Resilience::from_env(Provider::Snusbase, 250).unwrap()
+        });
+
+        let started = std::time::Instant::now();
+        let mut searches = tokio::task::JoinSet::new();
+        for index in 0..SEARCHES {
+            let snusbase = snusbase.clone();
+            searches.spawn(async move {
+                snusbase.get_by(&PII::Email, format!("user{index}@example.com"), false).await
+            });
+        }
+        while let Some(outcome) = searches.join_next().await {
+            outcome.unwrap().unwrap();
+        }
+        let elapsed = started.elapsed();
+
+        assert_eq!(peak.load(Ordering::SeqCst), LIMIT);
+        // One at a time would take SEARCHES * DELAY
+        assert!(elapsed < DELAY * (SEARCHES / LIMIT * 2) as u32, "took {elapsed:?}");
+    }
+
+    // A search that keeps failing spends most of its time backing off, and
+    //  mustn't hold the only permit while it does.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_retrying_search_leaves_its_permit_free_during_backoff () {
+        let mock = Router::new().route("/data/search", post(|Json(body): Json<Value>| async move {
+            if body.to_string().contains("flaky") {
+                return StatusCode::SERVICE_UNAVAILABLE.into_response();
+            }
+
+            Json(json!({ "took": 1, "size": 0, "results": {} })).into_response()
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, mock).await.unwrap() });
+
+        let snusbase = Arc::new(Snusbase {
+            api_key:  String::from("test"),
+            base_url: format!("http://{address}"),
+            client:   reqwest::Client::new(),
+            timeout:  Duration::from_secs(10),
+            permits:  Semaphore::new(1),
+            wildcard_min_literals: 4,
+            resilience: Resilience {
+                retry: RetryPolicy { retries: 2, backoff: Duration::from_secs(60) },
+                ..Resilience::from_env(Provider::Snusbase, 250).unwrap()
+            }
+        });
+
+        let flaky = tokio::spawn({
+            let snusbase = snusbase.clone();
+
+            async move { snusbase.get_by(&PII::Email, String::from("flaky@example.com"), false).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let search = snusbase.get_by(&PII::Email, String::from("user@example.com"), false);
+        tokio::time::timeout(Duration::from_secs(5), search).await
+            .expect("search waited on a permit held through backoff")
+            .unwrap();
+
+        flaky.abort();
+    }
+}
@@ -0,0 +1,324 @@
+use crate::apis::database::{ User, APIUsage, OperatorKey };
+use crate::apis::billing::{ BillingStore, BillingError };
+
+use std::sync::{ Arc, Mutex };
+
+use anyhow::{ Result, anyhow, Context };
+use async_trait::async_trait;
+use chrono::{ DateTime, Utc };
+use rusqlite::{ Connection, ErrorCode, OptionalExtension, Row, params };
+
+
+/// Ordered schema migrations. The index of each entry (plus one) is the
+///  `user_version` the database is at after applying it, so entries
+///  must only ever be appended.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE api_keys (
+        id      INTEGER PRIMARY KEY AUTOINCREMENT,
+        api_key TEXT    NOT NULL UNIQUE,
+        balance INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE api_usage (
+        id         INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id    INTEGER NOT NULL REFERENCES api_keys(id),
+        category   TEXT    NOT NULL,
+        service    TEXT    NOT NULL,
+        pii_type   TEXT    NOT NULL,
+        pii        TEXT    NOT NULL,
+        cost       INTEGER NOT NULL,
+        created_at TEXT    NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE INDEX api_usage_user_id ON api_usage(user_id);",
+    "CREATE TABLE operator_keys (
+        id           INTEGER PRIMARY KEY AUTOINCREMENT,
+        key_id       TEXT    NOT NULL UNIQUE,
+        name         TEXT    NOT NULL,
+        key_hash     TEXT    NOT NULL,
+        scopes       TEXT    NOT NULL,
+        created_at   TEXT    NOT NULL,
+        expires_at   TEXT,
+        last_used_at TEXT,
+        revoked_at   TEXT
+    );",
+    "ALTER TABLE api_usage ADD COLUMN request_id TEXT;"
+];
+const OPERATOR_KEY_COLUMNS: &str =
+    "key_id, name, key_hash, scopes, created_at, expires_at, last_used_at, revoked_at";
+
+#[derive(Debug, Clone)]
+pub struct SQLite {
+    connection: Arc<Mutex<Connection>>
+}
+impl SQLite {
+    pub fn new () -> Result<Self> {
+        let path = std::env::var("SQLITE_PATH")
+            .unwrap_or_else(|_| String::from("osint-api.db"));
+
+        Self::open(&path)
+    }
+    pub fn open ( path: &str ) -> Result<Self> {
+        let mut connection = Connection::open(path)
+            .context(format!("Failed to open SQLite database at `{path}`!"))?;
+
+        connection.pragma_update(None, "foreign_keys", "ON")
+            .context("Failed to enable foreign keys!")?;
+
+        Self::migrate(&mut connection)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection))
+        })
+    }
+    fn migrate ( connection: &mut Connection ) -> Result<()> {
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .context("Failed to read schema version!")?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let transaction = connection.transaction()
+                .context("Failed to start migration transaction!")?;
+
+            transaction.execute_batch(migration)
+                .context(format!("Failed to apply migration {}!", index + 1))?;
+            transaction.pragma_update(None, "user_version", index + 1)
+                .context("Failed to update schema version!")?;
+
+            transaction.commit()
+                .context("Failed to commit migration!")?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs `f` against the connection on the blocking pool.
+    async fn with_connection<T, F> ( &self, f: F ) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static
+    {
+        let connection = self.connection.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock()
+                .map_err(|_| anyhow!("SQLite connection lock was poisoned!"))?;
+
+            f(&mut connection)
+        }).await
+            .context("SQLite task panicked!")?
+    }
+}
+fn find_user ( connection: &Connection, user_api_key: &str ) -> Result<Option<User>> {
+    connection
+        .query_row(
+            "SELECT id, api_key, balance FROM api_keys WHERE api_key = ?1",
+            params![user_api_key],
+            |row| Ok(User {
+                id:      Some(row.get::<_, i64>(0)? as usize),
+                api_key: row.get(1)?,
+                balance: row.get(2)?
+            })
+        )
+        .optional()
+        .context("Failed to query user!")
+}
+fn operator_key_from_row ( row: &Row ) -> rusqlite::Result<(OperatorKey, String)> {
+    Ok((OperatorKey {
+        id:           row.get(0)?,
+        name:         row.get(1)?,
+        key_hash:     row.get(2)?,
+        scopes:       Vec::new(),
+        created_at:   row.get(4)?,
+        expires_at:   row.get(5)?,
+        last_used_at: row.get(6)?,
+        revoked_at:   row.get(7)?
+    }, row.get(3)?))
+}
+fn find_operator_key ( connection: &Connection, key_id: &str ) -> Result<Option<OperatorKey>> {
+    let found = connection
+        .query_row(
+            &format!("SELECT {OPERATOR_KEY_COLUMNS} FROM operator_keys WHERE key_id = ?1"),
+            params![key_id],
+            operator_key_from_row
+        )
+        .optional()
+        .context("Failed to query operator key!")?;
+
+    found
+        .map(|(key, scopes)| Ok(OperatorKey {
+            scopes: OperatorKey::scopes_from_str(&scopes)?,
+            ..key
+        }))
+        .transpose()
+}
+#[async_trait]
+impl BillingStore for SQLite {
+    async fn verify_db ( &self ) -> Result<()> {
+        self.with_connection(|connection| {
+            connection.query_row("SELECT COUNT(*) FROM api_keys", [], |row| row.get::<_, i64>(0))
+                .context("Failed to query the `api_keys` table!")?;
+
+            Ok(())
+        }).await
+    }
+    async fn get_user ( &self, user_api_key: String ) -> Result<User> {
+        self.with_connection(move |connection| {
+            find_user(connection, &user_api_key)?
+                .ok_or_else(|| BillingError::UnknownUser { api_key: user_api_key }.into())
+        }).await
+    }
+    async fn create_user ( &self, user: User ) -> Result<User> {
+        self.with_connection(move |connection| {
+            // The UNIQUE constraint is the check; a separate lookup first
+            //  would race another create for the same key
+            let inserted = connection.execute(
+                "INSERT INTO api_keys (api_key, balance) VALUES (?1, ?2)",
+                params![user.api_key, user.balance]
+            );
+            match inserted {
+                Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation =>
+                    return Err(BillingError::UserExists { api_key: user.api_key }.into()),
+                inserted => inserted.context("Failed to insert user!")?
+            };
+
+            Ok(User {
+                id: Some(connection.last_insert_rowid() as usize),
+                ..user
+            })
+        }).await
+    }
+    async fn offset_balance ( &self, user_api_key: String, amount: i32 ) -> Result<User> {
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    "UPDATE api_keys SET balance = MAX(balance + ?1, 0)
+                        WHERE api_key = ?2
+                        RETURNING id, api_key, balance",
+                    params![amount, user_api_key],
+                    |row| Ok(User {
+                        id:      Some(row.get::<_, i64>(0)? as usize),
+                        api_key: row.get(1)?,
+                        balance: row.get(2)?
+                    })
+                )
+                .optional()
+                .context("Failed to update balance!")?
+                .ok_or_else(|| BillingError::UnknownUser { api_key: user_api_key }.into())
+        }).await
+    }
+    async fn create_api_usage_log (
+        &self,
+        api_usage_log: APIUsage,
+        user_api_key: String
+    ) -> Result<()> {
+        let pii_type = serde_json::to_value(&api_usage_log.pii_type)
+            .context("Failed to serialize PII type!")?
+            .as_str()
+            .context("PII type did not serialize to a string!")?
+            .to_owned();
+
+        self.with_connection(move |connection| {
+            let user = find_user(connection, &user_api_key)?
+                .ok_or(BillingError::UnknownUser { api_key: user_api_key })?;
+
+            connection.execute(
+                "INSERT INTO api_usage (user_id, category, service, pii_type, pii, cost, request_id)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    user.id.context("User ID was not set!")? as i64,
+                    api_usage_log.category,
+                    api_usage_log.service,
+                    pii_type,
+                    api_usage_log.pii,
+                    api_usage_log.cost,
+                    api_usage_log.request_id
+                ]
+            ).context("Failed to insert API usage log!")?;
+
+            Ok(())
+        }).await
+    }
+
+    async fn create_operator_key ( &self, key: OperatorKey ) -> Result<OperatorKey> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO operator_keys (key_id, name, key_hash, scopes, created_at, expires_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    key.id,
+                    key.name,
+                    key.key_hash,
+                    OperatorKey::scopes_to_string(&key.scopes),
+                    key.created_at,
+                    key.expires_at
+                ]
+            ).context("Failed to insert operator key!")?;
+
+            Ok(key)
+        }).await
+    }
+    async fn get_operator_key ( &self, key_id: String ) -> Result<Option<OperatorKey>> {
+        self.with_connection(move |connection| {
+            find_operator_key(connection, &key_id)
+        }).await
+    }
+    async fn list_operator_keys ( &self ) -> Result<Vec<OperatorKey>> {
+        self.with_connection(|connection| {
+            let mut statement = connection
+                .prepare(&format!("SELECT {OPERATOR_KEY_COLUMNS} FROM operator_keys ORDER BY id"))
+                .context("Failed to prepare operator key query!")?;
+
+            let rows = statement.query_map([], operator_key_from_row)
+                .context("Failed to query operator keys!")?;
+
+            rows.map(|row| {
+                let (key, scopes) = row.context("Failed to read operator key!")?;
+
+                Ok(OperatorKey {
+                    scopes: OperatorKey::scopes_from_str(&scopes)?,
+                    ..key
+                })
+            }).collect()
+        }).await
+    }
+    async fn revoke_operator_key ( &self, key_id: String, at: DateTime<Utc> ) -> Result<OperatorKey> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE operator_keys SET revoked_at = COALESCE(revoked_at, ?1) WHERE key_id = ?2",
+                params![at, key_id]
+            ).context("Failed to revoke operator key!")?;
+
+            find_operator_key(connection, &key_id)?
+                .context(format!("Operator key `{key_id}` does not exist!"))
+        }).await
+    }
+    async fn touch_operator_key ( &self, key_id: String, at: DateTime<Utc> ) -> Result<()> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE operator_keys SET last_used_at = ?1 WHERE key_id = ?2",
+                params![at, key_id]
+            ).context("Failed to update operator key!")?;
+
+            Ok(())
+        }).await
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn creating_an_existing_user_conflicts () {
+        let path = std::env::temp_dir().join(format!("osint-api-{}.db", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+
+        // Two connections, as two processes sharing the file would have
+        let (first, second) = (SQLite::open(path).unwrap(), SQLite::open(path).unwrap());
+        let user = || User { api_key: "user".into(), balance: 10, id: None };
+
+        first.create_user(user()).await.unwrap();
+        let e = second.create_user(user()).await.unwrap_err();
+
+        let _ = std::fs::remove_file(path);
+        assert!(matches!(e.downcast_ref(), Some(BillingError::UserExists { .. })), "{e:?}");
+    }
+}
@@ -0,0 +1,159 @@
+use crate::helper::types::{ AppState, AppError, Scope };
+use crate::apis::database::{ OperatorKey, User };
+use crate::apis::billing::Reservation;
+
+use axum::{
+    async_trait,
+    extract::{ FromRequestParts, Request, State },
+    http::{ request::Parts, HeaderMap, HeaderValue },
+    middleware::Next,
+    response::{ IntoResponseParts, Response, ResponseParts }
+};
+use anyhow::anyhow;
+
+
+/// The operator key that authenticated this request.
+///
+/// Attached by the `operator_auth` layer; extracting it on a route that
+///  isn't behind that layer is a wiring bug and fails with a 500.
+#[derive(Clone, Debug)]
+pub struct OperatorAuth(pub OperatorKey);
+
+/// The end user a paid request is billed to, resolved from `User-API-Key`.
+///
+/// Attached by the `billable_user` layer, which also refuses to let a
+///  successful response through unless the handler settled a charge.
+#[derive(Clone, Debug)]
+pub struct BillableUser(pub User);
+impl BillableUser {
+    /// Holds `cost` against this user's balance until the reservation is
+    ///  committed through `AppState::commit_cost_and_log` or dropped.
+    pub async fn reserve (
+        &self,
+        app:  &AppState,
+        cost: i32
+    ) -> Result<Reservation, AppError> {
+        app.database
+            .reserve_balance(self.0.api_key.clone(), cost).await
+            .map_err(|e| match AppError::from(e) {
+                // On a paid route, an unknown user key is a bad credential
+                AppError::NotFound(e) => AppError::Unauthorized(e),
+                other => other
+            })
+    }
+}
+
+/// The `User-API-Key` header of an operator request that targets a user
+///  without billing them (e.g. looking up or funding their balance).
+#[derive(Clone, Debug)]
+pub struct UserApiKey(pub String);
+
+/// Proof that a paid handler committed its charge. Returned by
+///  `AppState::commit_cost_and_log` and handed back in the response,
+///  where it becomes the `X-Credits-Charged` header.
+#[derive(Clone, Copy, Debug)]
+pub struct Receipt {
+    pub cost: i32
+}
+impl IntoResponseParts for Receipt {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts ( self, mut res: ResponseParts ) -> Result<ResponseParts, Self::Error> {
+        res.extensions_mut().insert(self);
+
+        Ok(res)
+    }
+}
+
+/// Rejects the request unless its `Authorization` header holds a live
+///  operator key carrying `scope`, then attaches it as `OperatorAuth`.
+pub async fn operator_auth (
+    State((app, scope)): State<(AppState, Scope)>,
+    mut request: Request,
+    next:        Next
+) -> Result<Response, AppError> {
+    // Get the API key in the `Authorization` header
+    let api_key = header(request.headers(), "Authorization")
+        .map_err(AppError::Unauthorized)?;
+
+    // Check it, along with its expiry, revocation and scopes
+    let operator_key = app.operators
+        .authenticate(&api_key, scope).await?;
+
+    request.extensions_mut().insert(OperatorAuth(operator_key));
+
+    Ok(next.run(request).await)
+}
+
+/// Resolves the `User-API-Key` header to a `BillableUser` for a paid route.
+///
+/// A 2xx response without a `Receipt` means the handler never charged the
+///  user, so it is replaced with an error rather than served for free.
+pub async fn billable_user (
+    State(app): State<AppState>,
+    mut request: Request,
+    next:        Next
+) -> Result<Response, AppError> {
+    // Get the user's API key in the `User-API-Key` header
+    let user_api_key = header(request.headers(), "User-API-Key")
+        .map_err(AppError::Unauthorized)?;
+
+    let user = app.database
+        .get_user(user_api_key).await
+        .map_err(|e| match AppError::from(e) {
+            AppError::NotFound(e) => AppError::Unauthorized(e),
+            other => other
+        })?;
+
+    request.extensions_mut().insert(BillableUser(user));
+
+    let mut response = next.run(request).await;
+
+    if response.status().is_success() {
+        let receipt = response.extensions().get::<Receipt>().copied()
+            .ok_or_else(|| AppError::Internal(anyhow!("Paid route completed without charging the user!")))?;
+
+        response.headers_mut().insert("X-Credits-Charged", HeaderValue::from(receipt.cost));
+    }
+
+    Ok(response)
+}
+
+fn header ( headers: &HeaderMap, name: &str ) -> anyhow::Result<String> {
+    Ok(headers.get(name)
+        .ok_or_else(|| anyhow!("Missing \'{name}\' header!"))?
+        .to_str()
+        .map_err(|e| anyhow!("{e:?}"))?
+        .to_owned())
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for OperatorAuth {
+    type Rejection = AppError;
+
+    async fn from_request_parts ( parts: &mut Parts, _state: &S ) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<OperatorAuth>()
+            .cloned()
+            .ok_or_else(|| AppError::Internal(anyhow!("Route is not behind the operator auth layer!")))
+    }
+}
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for BillableUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts ( parts: &mut Parts, _state: &S ) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<BillableUser>()
+            .cloned()
+            .ok_or_else(|| AppError::Internal(anyhow!("Route is not behind the billable user layer!")))
+    }
+}
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for UserApiKey {
+    type Rejection = AppError;
+
+    async fn from_request_parts ( parts: &mut Parts, _state: &S ) -> Result<Self, Self::Rejection> {
+        header(&parts.headers, "User-API-Key")
+            .map(UserApiKey)
+            .map_err(AppError::BadRequest)
+    }
+}
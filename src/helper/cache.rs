@@ -0,0 +1,78 @@
+use crate::helper::types::AppError;
+
+use std::time::Duration;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{ request::Parts, header, HeaderValue },
+    response::{ IntoResponseParts, ResponseParts }
+};
+
+
+/// Whether the caller asked to skip the response cache, by sending
+///  `Cache-Control: no-cache` (or `no-store`, or `Pragma: no-cache`).
+///
+/// A forced lookup is still written back to the cache.
+#[derive(Clone, Copy, Debug)]
+pub struct ForceFresh(pub bool);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ForceFresh {
+    type Rejection = AppError;
+
+    async fn from_request_parts ( parts: &mut Parts, _state: &S ) -> Result<Self, Self::Rejection> {
+        let has_directive = |name: header::HeaderName, directives: &[&str]| {
+            parts.headers.get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|directive| directives.contains(&directive.trim().to_lowercase().as_str()))
+        };
+
+        Ok(ForceFresh(
+            has_directive(header::CACHE_CONTROL, &["no-cache", "no-store"])
+                || has_directive(header::PRAGMA, &["no-cache"])
+        ))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    /// The cache wasn't consulted, either because the caller forced a
+    ///  fresh lookup or because caching is disabled for the provider.
+    Bypass
+}
+
+/// How a response relates to the cache, reported as the `X-Cache` and
+///  `Cache-Control` headers.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheInfo {
+    pub status:  CacheStatus,
+    pub max_age: Duration
+}
+impl IntoResponseParts for CacheInfo {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts ( self, mut res: ResponseParts ) -> Result<ResponseParts, Self::Error> {
+        let status = match self.status {
+            CacheStatus::Hit    => "HIT",
+            CacheStatus::Miss   => "MISS",
+            CacheStatus::Bypass => "BYPASS"
+        };
+        let cache_control = if self.max_age.is_zero() {
+            String::from("no-store")
+        } else {
+            format!("private, max-age={}", self.max_age.as_secs())
+        };
+
+        res.headers_mut().insert("X-Cache", HeaderValue::from_static(status));
+        if let Ok(value) = HeaderValue::from_str(&cache_control) {
+            res.headers_mut().insert(header::CACHE_CONTROL, value);
+        }
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,13 @@
+use crate::helper::types::AppError;
+
+use axum::extract::FromRequestParts;
+
+/// `axum::extract::Path`, but rejections are reported as an `AppError`.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(AppError))]
+pub struct Path<T>(pub T);
+
+/// `axum::extract::Query`, but rejections are reported as an `AppError`.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(AppError))]
+pub struct Query<T>(pub T);
@@ -0,0 +1,208 @@
+use crate::helper::types::PII;
+
+use std::collections::{ BTreeMap, HashMap, HashSet };
+
+use anyhow::{ Result, Context, bail };
+use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+
+
+/// What a field in a provider record holds, independent of what that
+///  particular dump happens to call it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldCategory {
+    Username,
+    Email,
+    Phone,
+    Hash,
+    Salt,
+    Ip,
+    Name,
+    Password,
+    Address,
+    Company,
+    /// When the record was created or last seen, as the dump recorded it.
+    Observed,
+    Other
+}
+impl FieldCategory {
+    /// The category a searched-for term of `pii_type` falls into.
+    pub fn from_pii ( pii_type: &PII ) -> Option<Self> {
+        match pii_type {
+            PII::Username => Some(Self::Username),
+            PII::Email    => Some(Self::Email),
+            PII::Phone    => Some(Self::Phone),
+            PII::Hash     => Some(Self::Hash),
+            PII::Ip       => Some(Self::Ip),
+            PII::Name     => Some(Self::Name),
+            PII::Password => Some(Self::Password),
+            PII::Address  => Some(Self::Address),
+            PII::Company  => Some(Self::Company),
+            PII::Domain   => None
+        }
+    }
+}
+
+const DEFAULT_ALIASES: &[(FieldCategory, &[&str])] = &[
+    (FieldCategory::Username, &["username"]),
+    (FieldCategory::Email,    &["email"]),
+    (FieldCategory::Phone,    &["phone"]),
+    (FieldCategory::Hash,     &["hash"]),
+    (FieldCategory::Salt,     &["salt"]),
+    (FieldCategory::Ip,       &["ip", "lastip", "last_ip"]),
+    (FieldCategory::Name,     &["name"]),
+    (FieldCategory::Password, &["password"]),
+    (FieldCategory::Address,  &["address", "zip"]),
+    (FieldCategory::Company,  &["company", "org"]),
+    (FieldCategory::Observed, &["created", "created_at", "regdate", "date"])
+];
+
+/// Distinct values found per category.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct TallyCounts {
+    pub usernames: usize,
+    pub emails:    usize,
+    pub phones:    usize,
+    pub hashes:    usize,
+    pub salts:     usize,
+    pub ips:       usize,
+    pub names:     usize,
+    pub passwords: usize,
+    pub addresses: usize,
+    pub companies: usize,
+    pub other:     usize
+}
+impl TallyCounts {
+    pub fn add ( &mut self, category: FieldCategory, count: usize ) {
+        let slot = match category {
+            FieldCategory::Username => &mut self.usernames,
+            FieldCategory::Email    => &mut self.emails,
+            FieldCategory::Phone    => &mut self.phones,
+            FieldCategory::Hash     => &mut self.hashes,
+            FieldCategory::Salt     => &mut self.salts,
+            FieldCategory::Ip       => &mut self.ips,
+            FieldCategory::Name     => &mut self.names,
+            FieldCategory::Password => &mut self.passwords,
+            FieldCategory::Address  => &mut self.addresses,
+            FieldCategory::Company  => &mut self.companies,
+            // Dates aren't PII worth previewing on their own
+            FieldCategory::Observed |
+            FieldCategory::Other    => &mut self.other
+        };
+
+        *slot += count;
+    }
+}
+
+/// Counts for a whole result set, optionally broken down per dump.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Tally {
+    #[serde(flatten)]
+    pub counts: TallyCounts,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dumps:  Option<BTreeMap<String, TallyCounts>>
+}
+
+/// Maps provider field names (matched case-insensitively) to categories.
+///
+/// Starts from built-in aliases; `FIELD_SCHEMA_PATH` can point at a JSON
+///  object of extra aliases per category, e.g. `{"ip": ["regip"]}`.
+#[derive(Debug, Clone)]
+pub struct FieldSchema {
+    aliases: HashMap<String, FieldCategory>
+}
+impl FieldSchema {
+    pub fn from_env () -> Result<Self> {
+        let mut schema = Self::default();
+
+        if let Ok(path) = std::env::var("FIELD_SCHEMA_PATH") {
+            let contents = std::fs::read_to_string(&path)
+                .context(format!("Failed to read field schema at `{path}`!"))?;
+            let extra: HashMap<FieldCategory, Vec<String>> = serde_json::from_str(&contents)
+                .context(format!("Field schema at `{path}` is not valid!"))?;
+
+            for (category, aliases) in extra {
+                if category == FieldCategory::Other {
+                    bail!("Fields can't be aliased to `other`; it's whatever is left unclassified!");
+                }
+
+                for alias in aliases {
+                    schema.aliases.insert(alias.to_lowercase(), category);
+                }
+            }
+        }
+
+        Ok(schema)
+    }
+
+    pub fn classify ( &self, field: &str ) -> FieldCategory {
+        self.aliases
+            .get(&field.to_lowercase())
+            .copied()
+            .unwrap_or(FieldCategory::Other)
+    }
+
+    /// Counts the distinct values per category across `entries`, each a
+    ///  dump name and that record's fields. Values in `known` (e.g. the
+    ///  term that was searched for) aren't counted as findings.
+    pub fn tally<'a, E> (
+        &self,
+        entries:   impl IntoIterator<Item = (&'a str, E)>,
+        known:     &[(FieldCategory, Value)],
+        breakdown: bool
+    ) -> Tally
+    where
+        E: IntoIterator<Item = (&'a String, &'a Value)>
+    {
+        let known: HashSet<(FieldCategory, &Value)> = known.iter()
+            .map(|(category, value)| (*category, value))
+            .collect();
+
+        let mut found: HashSet<(FieldCategory, &Value)> = HashSet::new();
+        let mut found_per_dump: HashMap<&str, HashSet<(FieldCategory, &Value)>> = HashMap::new();
+
+        for (dump, fields) in entries {
+            for (field, value) in fields {
+                let finding = (self.classify(field), value);
+                if known.contains(&finding) {
+                    continue;
+                }
+
+                found.insert(finding);
+                if breakdown {
+                    found_per_dump.entry(dump).or_default().insert(finding);
+                }
+            }
+        }
+
+        let mut tally = Tally::default();
+        for (category, _) in found {
+            tally.counts.add(category, 1);
+        }
+        if breakdown {
+            tally.dumps = Some(found_per_dump.into_iter()
+                .map(|(dump, found)| {
+                    let mut counts = TallyCounts::default();
+                    for (category, _) in found {
+                        counts.add(category, 1);
+                    }
+
+                    (dump.to_owned(), counts)
+                })
+                .collect());
+        }
+
+        tally
+    }
+}
+impl Default for FieldSchema {
+    fn default () -> Self {
+        let aliases = DEFAULT_ALIASES.iter()
+            .flat_map(|(category, aliases)| aliases.iter()
+                .map(move |alias| (alias.to_string(), *category)))
+            .collect();
+
+        Self { aliases }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::helper::fields::{ FieldSchema, FieldCategory };
+use crate::helper::types::Provider;
+
+use std::collections::BTreeMap;
+
+use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+
+
+/// Who a finding describes, with each field typed by what it holds rather
+///  than what the provider happened to call it.
+///
+/// Each typed field keeps the first value seen; further values for the
+///  same category, and anything unclassified, are kept in `other` under
+///  their original field name so nothing is lost.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Identity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email:    Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phone:    Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name:     Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip:       Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash:     Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub salt:     Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address:  Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub company:  Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub other:    BTreeMap<String, Value>
+}
+impl Identity {
+    /// Builds an identity from a provider record, classifying each field
+    ///  through `schema`. Observed dates are returned separately since
+    ///  they describe the record rather than the person.
+    pub fn from_fields<'a> (
+        schema: &FieldSchema,
+        fields: impl IntoIterator<Item = (&'a String, &'a Value)>
+    ) -> (Self, Option<String>) {
+        let mut identity = Self::default();
+        let mut observed = None;
+
+        for (field, value) in fields {
+            // Nested values have no single category to go in
+            let text = match value {
+                Value::Array(_) | Value::Object(_) => {
+                    identity.other.insert(field.clone(), value.clone());
+
+                    continue;
+                },
+                _ => match as_text(value) {
+                    Some(text) => text,
+                    None => continue
+                }
+            };
+
+            let slot = match schema.classify(field) {
+                FieldCategory::Email    => &mut identity.email,
+                FieldCategory::Username => &mut identity.username,
+                FieldCategory::Phone    => &mut identity.phone,
+                FieldCategory::Name     => &mut identity.name,
+                FieldCategory::Ip       => &mut identity.ip,
+                FieldCategory::Hash     => &mut identity.hash,
+                FieldCategory::Salt     => &mut identity.salt,
+                FieldCategory::Password => &mut identity.password,
+                FieldCategory::Address  => &mut identity.address,
+                FieldCategory::Company  => &mut identity.company,
+                FieldCategory::Observed => &mut observed,
+                FieldCategory::Other    => {
+                    identity.other.insert(field.clone(), value.clone());
+
+                    continue;
+                }
+            };
+
+            match slot {
+                None => *slot = Some(text),
+                Some(existing) if *existing == text => {},
+                Some(_) => {
+                    identity.other.insert(field.clone(), value.clone());
+                }
+            }
+        }
+
+        (identity, observed)
+    }
+}
+
+/// One record a provider returned, in a shape that's the same whichever
+///  provider it came from.
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub provider: Provider,
+    /// The dump (or site, or lookup) the record came from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source:   Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url:      Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed: Option<String>,
+    pub identity: Identity
+}
+
+/// Converts a provider's raw response into findings.
+pub trait Normalize {
+    fn normalize ( &self, schema: &FieldSchema ) -> Vec<Finding>;
+}
+
+/// Response format a lookup route returns, picked with `?format=`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// The provider's own response, as before.
+    #[default]
+    Raw,
+    /// A list of `Finding`s, the same shape for every provider.
+    Normalized
+}
+#[derive(Debug, Default, Deserialize)]
+pub struct FormatParams {
+    #[serde(default)]
+    pub format: Format
+}
+impl Format {
+    pub fn apply<T: Normalize> ( self, raw: T, schema: &FieldSchema ) -> Formatted<T> {
+        match self {
+            Format::Raw        => Formatted::Raw(raw),
+            Format::Normalized => Formatted::Normalized(Normalized {
+                findings: raw.normalize(schema)
+            })
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Normalized {
+    pub findings: Vec<Finding>
+}
+
+/// A lookup response in whichever format was asked for.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Formatted<T> {
+    Raw(T),
+    Normalized(Normalized)
+}
+
+/// A scalar field value as text, skipping values that carry nothing.
+fn as_text ( value: &Value ) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(text) if text.trim().is_empty() => None,
+        Value::String(text) => Some(text.clone()),
+        other => Some(other.to_string())
+    }
+}
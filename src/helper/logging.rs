@@ -0,0 +1,212 @@
+use crate::helper::request_id;
+
+use std::fmt;
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+use anyhow::{ Result, Context, anyhow, bail };
+use chrono::{ SecondsFormat, Utc };
+use serde_json::{ Map, Value };
+use sha2::{ Digest, Sha256 };
+use tracing::{ Event, Subscriber, field::{ Field, Visit } };
+use tracing_subscriber::{
+    fmt::{ format::Writer, FmtContext, FormatEvent, FormatFields },
+    registry::LookupSpan,
+    EnvFilter
+};
+
+
+/// Fields whose values are search terms or could identify someone. They're
+///  logged as a fingerprint, so one run's lines about the same value can
+///  still be matched up.
+const PII_FIELDS: &[&str] = &[
+    "pii", "term", "terms", "username", "email", "phone", "ip", "password", "hash", "name", "url"
+];
+/// Fields holding a provider's raw response, left out entirely.
+const PAYLOAD_FIELDS: &[&str] = &[ "payload" ];
+
+/// Set once at startup from `LOG_DEBUG_PAYLOADS`.
+static DEBUG_PAYLOADS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Json,
+    Text
+}
+
+/// Formats each event as one line, JSON or text, tagged with the current
+///  request's ID and with PII and provider payloads redacted.
+#[derive(Debug)]
+struct Formatter {
+    format: Format,
+    /// Debug mode: log PII and payloads as they are.
+    raw:    bool,
+    /// Random per process, so fingerprints can't be looked up across runs.
+    salt:   [u8; 16]
+}
+impl Formatter {
+    fn redact ( &self, name: &str, value: Value ) -> Option<Value> {
+        if self.raw {
+            return Some(value);
+        }
+        if PAYLOAD_FIELDS.contains(&name) {
+            return None;
+        }
+        if !PII_FIELDS.contains(&name) {
+            return Some(value);
+        }
+
+        let text = match value {
+            Value::String(text) => text,
+            value => value.to_string()
+        };
+        let digest = Sha256::new()
+            .chain_update(self.salt)
+            .chain_update(text.as_bytes())
+            .finalize();
+        let fingerprint: String = digest[..4].iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        Some(Value::String(format!("[redacted:{fingerprint}]")))
+    }
+}
+
+/// Collects an event's message and fields, redacting as it goes.
+struct Fields<'a> {
+    formatter: &'a Formatter,
+    message:   String,
+    fields:    Map<String, Value>
+}
+impl Fields<'_> {
+    fn insert ( &mut self, field: &Field, value: Value ) {
+        if field.name() == "message" {
+            self.message = match value {
+                Value::String(message) => message,
+                value => value.to_string()
+            };
+
+            return;
+        }
+
+        if let Some(value) = self.formatter.redact(field.name(), value) {
+            self.fields.insert(field.name().to_owned(), value);
+        }
+    }
+}
+impl Visit for Fields<'_> {
+    fn record_str ( &mut self, field: &Field, value: &str ) {
+        self.insert(field, Value::from(value));
+    }
+    fn record_i64 ( &mut self, field: &Field, value: i64 ) {
+        self.insert(field, Value::from(value));
+    }
+    fn record_u64 ( &mut self, field: &Field, value: u64 ) {
+        self.insert(field, Value::from(value));
+    }
+    fn record_f64 ( &mut self, field: &Field, value: f64 ) {
+        self.insert(field, Value::from(value));
+    }
+    fn record_bool ( &mut self, field: &Field, value: bool ) {
+        self.insert(field, Value::from(value));
+    }
+    fn record_error ( &mut self, field: &Field, value: &(dyn std::error::Error + 'static) ) {
+        self.insert(field, Value::from(value.to_string()));
+    }
+    fn record_debug ( &mut self, field: &Field, value: &dyn fmt::Debug ) {
+        self.insert(field, Value::from(format!("{value:?}")));
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for Formatter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static
+{
+    fn format_event ( &self, _ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_> ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut fields = Fields { formatter: self, message: String::new(), fields: Map::new() };
+        event.record(&mut fields);
+
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let request_id = request_id::current();
+
+        match self.format {
+            Format::Json => {
+                let mut line = Map::new();
+                line.insert("timestamp".into(), timestamp.into());
+                line.insert("level".into(), metadata.level().as_str().into());
+                line.insert("target".into(), metadata.target().into());
+                line.insert("message".into(), fields.message.into());
+                if let Some(id) = request_id {
+                    line.insert("request_id".into(), id.into());
+                }
+                line.extend(fields.fields);
+
+                writeln!(writer, "{}", Value::Object(line))
+            },
+            Format::Text => {
+                write!(writer, "{timestamp} {:>5} {}: {}", metadata.level(), metadata.target(), fields.message)?;
+                for (name, value) in fields.fields {
+                    match value {
+                        Value::String(text) if !text.contains(char::is_whitespace) => write!(writer, " {name}={text}")?,
+                        value => write!(writer, " {name}={value}")?
+                    }
+                }
+                if let Some(id) = request_id {
+                    write!(writer, " request_id={id}")?;
+                }
+
+                writeln!(writer)
+            }
+        }
+    }
+}
+
+/// Installs the process's logger, configured by `LOG_LEVEL` (filter
+///  directives, e.g. `info` or `warn,osint_api=debug`), `LOG_FORMAT`
+///  (`json` or `text`) and `LOG_DEBUG_PAYLOADS`, which turns off redaction.
+pub fn init () -> Result<()> {
+    let level = std::env::var("LOG_LEVEL")
+        .unwrap_or_else(|_| "info".to_string());
+    let filter = EnvFilter::try_new(&level)
+        .context("LOG_LEVEL is not a valid filter!")?;
+
+    let format = match std::env::var("LOG_FORMAT") {
+        Ok(value) => match value.trim().to_lowercase().as_str() {
+            "json" => Format::Json,
+            "text" => Format::Text,
+            _ => bail!("LOG_FORMAT must be json or text!")
+        },
+        Err(_) => Format::Json
+    };
+
+    let raw = match std::env::var("LOG_DEBUG_PAYLOADS") {
+        Ok(value) => match value.trim().to_lowercase().as_str() {
+            "true" | "1"  => true,
+            "false" | "0" => false,
+            _ => bail!("LOG_DEBUG_PAYLOADS must be true or false!")
+        },
+        Err(_) => false
+    };
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .event_format(Formatter { format, raw, salt: fastrand::u128(..).to_le_bytes() })
+        .with_writer(std::io::stdout)
+        .try_init()
+        .map_err(|e| anyhow!(e))
+        .context("Failed to install the logger!")?;
+
+    DEBUG_PAYLOADS.store(raw, Ordering::Relaxed);
+    if raw {
+        tracing::warn!("LOG_DEBUG_PAYLOADS is on; PII and raw provider payloads will be logged!");
+    }
+
+    Ok(())
+}
+
+/// Whether provider payloads are logged at all; checked before
+///  serializing one just to log it.
+pub fn payloads_enabled () -> bool {
+    DEBUG_PAYLOADS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,226 @@
+use crate::helper::types::Provider;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{ Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+
+use axum::{
+    extract::{ MatchedPath, Request },
+    middleware::Next,
+    response::Response
+};
+
+
+/// Latency histogram buckets, in seconds. Sherlock lookups take minutes,
+///  so the top end is long.
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0
+];
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    /// Per bucket, not cumulative; the last one is `+Inf`.
+    buckets: Vec<u64>,
+    sum:     f64,
+    count:   u64
+}
+impl Histogram {
+    fn new () -> Self {
+        Self { buckets: vec!(0; BUCKETS.len() + 1), sum: 0.0, count: 0 }
+    }
+    fn observe ( &mut self, elapsed: Duration ) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = BUCKETS.iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(BUCKETS.len());
+
+        self.buckets[bucket] += 1;
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// By method, matched route and status.
+    requests: BTreeMap<(String, String, u16), Histogram>,
+    /// By provider, operation and outcome.
+    upstream: BTreeMap<(&'static str, &'static str, &'static str), Histogram>,
+    /// By category and service, as in the usage logs.
+    credits:  BTreeMap<(String, String), u64>,
+    /// By provider and whether it was a hit.
+    cache:    BTreeMap<(&'static str, bool), u64>,
+    balance_rejections: u64
+}
+
+/// Process-wide counters and histograms, exposed on `/metrics` in the
+///  Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>
+}
+
+/// The process's metrics.
+pub fn metrics () -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+    METRICS.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    pub fn observe_request ( &self, method: &str, route: &str, status: u16, elapsed: Duration ) {
+        self.lock().requests
+            .entry((method.to_owned(), route.to_owned(), status))
+            .or_insert_with(Histogram::new)
+            .observe(elapsed);
+    }
+    /// One call (or retry) to a provider; `outcome` is `ok`, `error`, or
+    ///  `rejected` if its circuit breaker refused it.
+    pub fn observe_upstream ( &self, provider: Provider, operation: &'static str, outcome: &'static str, elapsed: Duration ) {
+        self.lock().upstream
+            .entry((provider.as_str(), operation, outcome))
+            .or_insert_with(Histogram::new)
+            .observe(elapsed);
+    }
+    pub fn add_credits ( &self, category: &str, service: &str, cost: i32 ) {
+        *self.lock().credits
+            .entry((category.to_owned(), service.to_owned()))
+            .or_default() += cost.max(0) as u64;
+    }
+    pub fn count_cache ( &self, provider: Provider, hit: bool ) {
+        *self.lock().cache
+            .entry((provider.as_str(), hit))
+            .or_default() += 1;
+    }
+    pub fn count_balance_rejection ( &self ) {
+        self.lock().balance_rejections += 1;
+    }
+
+    /// Writes every metric recorded so far.
+    pub fn write ( &self, out: &mut Exposition ) {
+        let registry = self.lock();
+
+        out.family("osint_http_requests_seconds", "histogram", "HTTP requests handled, by route and status.");
+        for ((method, route, status), histogram) in &registry.requests {
+            out.histogram("osint_http_requests_seconds", &[
+                ("method", method),
+                ("route", route),
+                ("status", &status.to_string())
+            ], histogram);
+        }
+
+        out.family("osint_upstream_calls_seconds", "histogram", "Calls to upstream providers, by operation and outcome.");
+        for ((provider, operation, outcome), histogram) in &registry.upstream {
+            out.histogram("osint_upstream_calls_seconds", &[
+                ("provider", provider),
+                ("operation", operation),
+                ("outcome", outcome)
+            ], histogram);
+        }
+
+        out.family("osint_credits_charged_total", "counter", "Credits charged to users, by usage category and service.");
+        for ((category, service), credits) in &registry.credits {
+            out.sample("osint_credits_charged_total", &[ ("category", category), ("service", service) ], *credits);
+        }
+
+        out.family("osint_cache_lookups_total", "counter", "Response cache lookups, by provider and result.");
+        for ((provider, hit), count) in &registry.cache {
+            out.sample("osint_cache_lookups_total", &[
+                ("provider", provider),
+                ("result", if *hit { "hit" } else { "miss" })
+            ], *count);
+        }
+
+        out.family("osint_cache_hit_ratio", "gauge", "Share of response cache lookups that hit, by provider.");
+        for provider in Provider::ALL {
+            let count = |hit: bool| registry.cache.get(&(provider.as_str(), hit)).copied().unwrap_or(0);
+            let (hits, total) = (count(true), count(true) + count(false));
+
+            if total > 0 {
+                out.sample("osint_cache_hit_ratio", &[ ("provider", provider.as_str()) ], hits as f64 / total as f64);
+            }
+        }
+
+        out.family("osint_balance_rejections_total", "counter", "Lookups refused because the user's balance was too low.");
+        out.sample("osint_balance_rejections_total", &[], registry.balance_rejections);
+    }
+
+    fn lock ( &self ) -> std::sync::MutexGuard<'_, Registry> {
+        self.registry.lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Builds a Prometheus text exposition.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    text: String
+}
+impl Exposition {
+    pub fn family ( &mut self, name: &str, kind: &str, help: &str ) {
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {kind}");
+    }
+    pub fn sample ( &mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display ) {
+        let _ = writeln!(self.text, "{name}{} {value}", format_labels(labels));
+    }
+    fn histogram ( &mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram ) {
+        let mut cumulative = 0;
+        for (index, count) in histogram.buckets.iter().enumerate() {
+            cumulative += count;
+
+            let bound = BUCKETS.get(index)
+                .map_or("+Inf".to_string(), |bound| bound.to_string());
+            let mut labels = labels.to_vec();
+            labels.push(("le", &bound));
+
+            self.sample(&format!("{name}_bucket"), &labels, cumulative);
+        }
+
+        self.sample(&format!("{name}_sum"), labels, histogram.sum);
+        self.sample(&format!("{name}_count"), labels, histogram.count);
+    }
+    pub fn finish ( self ) -> String {
+        self.text
+    }
+}
+
+fn format_labels ( labels: &[(&str, &str)] ) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels: Vec<String> = labels.iter()
+        .map(|(name, value)| format!(
+            "{name}=\"{}\"",
+            value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+        ))
+        .collect();
+
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Times and logs every request, labelled by its route pattern (not the
+///  raw path, which would contain search terms).
+pub async fn track_requests (
+    request: Request,
+    next:    Next
+) -> Response {
+    let method = request.method().to_string();
+    let route = request.extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_string(), |path| path.as_str().to_owned());
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let (status, elapsed) = (response.status().as_u16(), started.elapsed());
+    metrics().observe_request(&method, &route, status, elapsed);
+    tracing::info!(
+        method = %method, route = %route, status, elapsed_ms = elapsed.as_millis() as u64,
+        "Handled request"
+    );
+
+    response
+}
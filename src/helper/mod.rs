@@ -1 +1,11 @@
-pub mod types;
\ No newline at end of file
+pub mod types;
+pub mod request_id;
+pub mod extract;
+pub mod auth;
+pub mod pricing;
+pub mod cache;
+pub mod fields;
+pub mod findings;
+pub mod usernames;
+pub mod metrics;
+pub mod logging;
@@ -0,0 +1,104 @@
+use crate::helper::types::Provider;
+
+use std::str::FromStr;
+
+use anyhow::{ Result, Context, bail };
+
+
+/// Deployment-tunable pricing and limits, read once at startup.
+#[derive(Debug, Clone)]
+pub struct Pricing {
+    pub batch_max_terms:        usize,
+    pub batch_discount_percent: i32,
+    pub cache_hit_percent:      i32,
+
+    pub snusbase_preview_price: i32,
+    pub bulkvs_preview_price:   i32,
+    pub sherlock_preview_price: i32,
+
+    pub pivot_max_depth:    usize,
+    pub pivot_max_entities: usize
+}
+impl Pricing {
+    pub fn from_env () -> Result<Self> {
+        let batch_max_terms = number_from_env("SNUSBASE_BATCH_MAX_TERMS", 25)?;
+        if batch_max_terms == 0 {
+            bail!("SNUSBASE_BATCH_MAX_TERMS must be at least 1!");
+        }
+
+        let batch_discount_percent = number_from_env("SNUSBASE_BATCH_DISCOUNT_PERCENT", 0)?;
+        if !(0..=100).contains(&batch_discount_percent) {
+            bail!("SNUSBASE_BATCH_DISCOUNT_PERCENT must be between 0 and 100!");
+        }
+
+        let cache_hit_percent = number_from_env("CACHE_HIT_PRICE_PERCENT", 100)?;
+        if !(0..=100).contains(&cache_hit_percent) {
+            bail!("CACHE_HIT_PRICE_PERCENT must be between 0 and 100!");
+        }
+
+        let preview_price = |var: &str| -> Result<i32> {
+            let price = number_from_env(var, 0)?;
+            if price < 0 {
+                bail!("{var} must not be negative!");
+            }
+
+            Ok(price)
+        };
+
+        let pivot_max_depth = number_from_env("PIVOT_MAX_DEPTH", 3)?;
+        if pivot_max_depth == 0 {
+            bail!("PIVOT_MAX_DEPTH must be at least 1!");
+        }
+
+        let pivot_max_entities = number_from_env("PIVOT_MAX_ENTITIES", 200)?;
+        if pivot_max_entities == 0 {
+            bail!("PIVOT_MAX_ENTITIES must be at least 1!");
+        }
+
+        Ok(Self {
+            batch_max_terms,
+            batch_discount_percent,
+            cache_hit_percent,
+
+            snusbase_preview_price: preview_price("SNUSBASE_PREVIEW_PRICE")?,
+            bulkvs_preview_price:   preview_price("BULKVS_PREVIEW_PRICE")?,
+            sherlock_preview_price: preview_price("SHERLOCK_PREVIEW_PRICE")?,
+
+            pivot_max_depth,
+            pivot_max_entities
+        })
+    }
+
+    /// Cost of `terms` lookups at `per_term` each, less the batch discount,
+    ///  rounded up to the nearest credit.
+    pub fn batch_cost ( &self, per_term: i32, terms: usize ) -> i32 {
+        let full = per_term * terms as i32;
+
+        (full * (100 - self.batch_discount_percent) + 99) / 100
+    }
+    /// What a tally against `provider` costs.
+    pub fn preview_price ( &self, provider: Provider ) -> i32 {
+        match provider {
+            Provider::Snusbase => self.snusbase_preview_price,
+            Provider::BulkVS   => self.bulkvs_preview_price,
+            Provider::Sherlock => self.sherlock_preview_price
+        }
+    }
+    /// What a lookup normally costing `cost` is charged when served from
+    ///  the response cache, rounded up to the nearest credit.
+    pub fn cache_hit_cost ( &self, cost: i32 ) -> i32 {
+        (cost * self.cache_hit_percent + 99) / 100
+    }
+}
+
+pub fn number_from_env<T> ( var: &str, default: T ) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static
+{
+    match std::env::var(var) {
+        Ok(value) => value.parse::<T>()
+            .context(format!("{var} must be a whole number!")),
+        Err(_) => Ok(default)
+    }
+}
@@ -0,0 +1,65 @@
+use std::future::Future;
+
+use axum::{
+    extract::Request,
+    http::HeaderValue,
+    middleware::Next,
+    response::Response
+};
+use reqwest::RequestBuilder;
+use uuid::Uuid;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request currently being handled, if any.
+pub fn current () -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Carries the current request's ID into `future`; task-locals aren't
+///  inherited by spawned tasks, so wrap anything spawned on its behalf.
+pub fn propagate<F: Future> ( future: F ) -> impl Future<Output = F::Output> {
+    let id = current();
+
+    async move {
+        match id {
+            Some(id) => REQUEST_ID.scope(id, future).await,
+            None => future.await
+        }
+    }
+}
+
+/// Passes the current request's ID on to an upstream call, so both sides'
+///  logs can be matched up.
+pub fn forward ( request: RequestBuilder ) -> RequestBuilder {
+    match current() {
+        Some(id) => request.header("X-Request-Id", id),
+        None => request
+    }
+}
+
+/// Assigns every request an ID (reusing a sane inbound `X-Request-Id`),
+///  makes it available through `current` for the rest of the request,
+///  and echoes it back in the `X-Request-Id` response header.
+pub async fn request_id (
+    request: Request,
+    next:    Next
+) -> Response {
+    let id = request.headers()
+        .get("X-Request-Id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 128
+            && value.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_'))
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).await;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("X-Request-Id", value);
+    }
+
+    response
+}
@@ -2,123 +2,220 @@ use crate::apis::{
     Snusbase,
     Sherlock,
     BulkVS,
-    NocoDB
+    Billing,
+    Operators,
+    Jobs,
+    HttpClients
 };
+use crate::apis::operators::OperatorKeyError;
+use crate::apis::resilience::{ BreakerOpen, Resilience };
+use crate::apis::health::describe;
 use crate::apis::database::APIUsage;
+use crate::apis::billing::{ Reservation, BillingError };
+use crate::helper::auth::{ BillableUser, Receipt };
+use crate::helper::cache::{ CacheInfo, CacheStatus, ForceFresh };
+use crate::apis::cache::{ CacheHit, CacheKey, ResponseCache };
+use crate::helper::pricing::Pricing;
+use crate::helper::fields::FieldSchema;
+use crate::helper::usernames::UsernameRejection;
+use crate::helper::metrics::metrics;
 
 
+use std::future::Future;
 use std::sync::Arc;
 use axum::{
     http::StatusCode,
-    http::HeaderMap,
+    extract::rejection::{
+        JsonRejection,
+        PathRejection,
+        QueryRejection
+    },
     response::{
         IntoResponse,
         Response
     },
+    Json
 };
-use tokio::sync::Mutex;
-use anyhow::{ Result, anyhow, Context };
-use serde::{ Serialize, Deserialize };
+use serde_json::json;
+use anyhow::{ Result, anyhow };
+use serde::{ Serialize, Deserialize, de::DeserializeOwned };
 
+/// Shared, lock-free handles to each provider. The clients are immutable
+///  configuration; each bounds its own in-flight calls with a semaphore.
 #[derive(Clone)]
 pub struct AppState {
-    pub sherlock: Arc<Mutex<Sherlock>>,
-    pub snusbase: Arc<Mutex<Snusbase>>,
-    pub bulkvs:   Arc<Mutex<BulkVS>>,
-    pub database: Arc<Mutex<NocoDB>>
+    pub sherlock: Arc<Sherlock>,
+    pub snusbase: Arc<Snusbase>,
+    pub bulkvs:   Arc<BulkVS>,
+    pub database:  Arc<Billing>,
+    pub operators: Arc<Operators>,
+    pub pricing:   Arc<Pricing>,
+    pub cache:     Arc<ResponseCache>,
+    pub fields:    Arc<FieldSchema>,
+    pub jobs:      Arc<Jobs>,
+    pub clients:   HttpClients
 }
 impl AppState {
-    pub fn verify_api_key (
-        &self,
-        api_key: String
-    ) -> Result<bool> {
-        // Check if the API key is in the list of valid keys
-        Ok(std::env::var("API_KEYS")
-            .context("Missing API_KEYS env variable!")?
-            .split(',')
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>()
-            .contains(&api_key))
-    }
-    pub fn verify_api_key_header (
+    /// `provider`'s health, breaker and retry policy.
+    pub fn resilience ( &self, provider: Provider ) -> &Resilience {
+        match provider {
+            Provider::Snusbase => &self.snusbase.resilience,
+            Provider::BulkVS   => &self.bulkvs.resilience,
+            Provider::Sherlock => &self.sherlock.resilience
+        }
+    }
+    /// Serves a lookup from the response cache when possible (billed at the
+    ///  cache-hit price), otherwise runs `fetch` at full price and caches
+    ///  its result. Either way the user is charged and the usage logged.
+    pub async fn billed_lookup<T, F> (
         &self,
-        headers: &HeaderMap,
-    ) -> Result<()> {
-        // Get the API key in the `Authorization` header
-        let api_key = headers.get("Authorization")
-            .ok_or_else(|| anyhow!("Missing \'Authorization\' header!"))?
-            .to_str()
-            .map_err(|e| anyhow!("{e:?}"))?
-            .to_owned();
-        
-        // Check it
-        if !self.verify_api_key(api_key).context("Failed to verify API key!")? {
-            return Err(anyhow!("Invalid API key!").into());
+        user:  &BillableUser,
+        key:   CacheKey,
+        fresh: ForceFresh,
+        cost:  i32,
+        (category, service, pii_type, pii): (String, String, PII, String),
+        fetch: F
+    ) -> Result<(Receipt, CacheInfo, T), AppError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = Result<T, AppError>>
+    {
+        let hit = match fresh {
+            ForceFresh(true) => None,
+            ForceFresh(false) => self.cache.get::<T>(&key).await
+        };
+
+        match hit {
+            Some(CacheHit { value, remaining }) => {
+                let reservation = user.reserve(self, self.pricing.cache_hit_cost(cost)).await?;
+
+                let receipt = self.commit_cost_and_log(
+                    reservation,
+                    (category, format!("{service}_Cached"), pii_type, pii)
+                ).await?;
+
+                Ok((receipt, CacheInfo { status: CacheStatus::Hit, max_age: remaining }, value))
+            },
+            None => {
+                // Don't hold the user's balance for a call that would be refused
+                self.resilience(key.provider).breaker.check()?;
+                let reservation = user.reserve(self, cost).await?;
+
+                self.fetch_and_commit(reservation, key, fresh, (category, service, pii_type, pii), fetch).await
+            }
         }
+    }
+    /// Like `billed_lookup`, but against a reservation already held for
+    ///  the full cost (e.g. when a job was submitted). A cache hit shrinks
+    ///  it to the cache-hit price before it's committed.
+    pub async fn settle_lookup<T, F> (
+        &self,
+        mut reservation: Reservation,
+        key:   CacheKey,
+        fresh: ForceFresh,
+        (category, service, pii_type, pii): (String, String, PII, String),
+        fetch: F
+    ) -> Result<(Receipt, CacheInfo, T), AppError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = Result<T, AppError>>
+    {
+        let hit = match fresh {
+            ForceFresh(true) => None,
+            ForceFresh(false) => self.cache.get::<T>(&key).await
+        };
+
+        match hit {
+            Some(CacheHit { value, remaining }) => {
+                reservation.reduce_to(self.pricing.cache_hit_cost(reservation.cost));
 
-        Ok(())
+                let receipt = self.commit_cost_and_log(
+                    reservation,
+                    (category, format!("{service}_Cached"), pii_type, pii)
+                ).await?;
+
+                Ok((receipt, CacheInfo { status: CacheStatus::Hit, max_age: remaining }, value))
+            },
+            None => self.fetch_and_commit(reservation, key, fresh, (category, service, pii_type, pii), fetch).await
+        }
     }
-    pub async fn verify_user_api_key_has_balance (
+    async fn fetch_and_commit<T, F> (
         &self,
-        app:     &AppState,
-        headers: &HeaderMap,
-        cost:    i32
-    ) -> Result<()> {
-        // Get the user's API key in the `Authorization` header
-        let user_api_key = headers.get("User-API-Key")
-            .ok_or_else(|| anyhow!("Missing \'User-API-Key\' header!"))?
-            .to_str()
-            .map_err(|e| anyhow!("{e:?}"))?
-            .to_owned();
-    
-        // Get and print the user's balance
-        let user = app.database
-            .lock().await
-            .get_user(user_api_key.clone())?;
-        
-        // Check if the user has enough balance
-        if user.balance < cost {
-            return Err(anyhow!("Balance {} is insufficient for cost {}!", user.balance, cost).into());
+        reservation: Reservation,
+        key:   CacheKey,
+        fresh: ForceFresh,
+        usage: (String, String, PII, String),
+        fetch: F
+    ) -> Result<(Receipt, CacheInfo, T), AppError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = Result<T, AppError>>
+    {
+        let value = fetch.await?;
+        if crate::helper::logging::payloads_enabled() {
+            tracing::debug!(service = %usage.1, payload = %json!(value), "Provider response");
         }
+        let ttl = self.cache.put(&key, &value).await;
 
-        Ok(())
+        let receipt = self.commit_cost_and_log(reservation, usage).await?;
+
+        let status = if fresh.0 || ttl.is_zero() { CacheStatus::Bypass } else { CacheStatus::Miss };
+        Ok((receipt, CacheInfo { status, max_age: ttl }, value))
+    }
+    /// Deducts a reservation and records the usage. Paid handlers must hand
+    ///  the returned `Receipt` back with their response.
+    pub async fn commit_cost_and_log(
+        &self,
+        reservation: Reservation,
+        usage: (String, String, PII, String)
+    ) -> Result<Receipt> {
+        self.commit_costs_and_log(reservation, vec!(usage)).await
     }
-    pub async fn deduct_cost_and_log(
+    /// Like `commit_cost_and_log`, but for a reservation covering several
+    ///  lookups. The reserved cost is split across one log entry per lookup;
+    ///  with no lookups, nothing is charged.
+    pub async fn commit_costs_and_log(
         &self,
-        app:     &AppState,
-        headers: &HeaderMap,
-        (category, service, pii_type, pii, cost): (String, String, PII, String, i32)
-    ) -> Result<()> {
-        let user_api_key = headers.get("User-API-Key")
-            .ok_or_else(|| anyhow!("Missing \'User-API-Key\' header!"))?
-            .to_str()
-            .map_err(|e| anyhow!("{e:?}"))?
-            .to_owned();
+        reservation: Reservation,
+        usages: Vec<(String, String, PII, String)>
+    ) -> Result<Receipt> {
+        if usages.is_empty() {
+            reservation.release();
+
+            return Ok(Receipt { cost: 0 });
+        }
+
+        let user_api_key = reservation.user_api_key.clone();
+        let cost = reservation.cost;
 
         // Deduct the cost
-        app.database
-            .lock().await
-            .offset_balance(user_api_key.clone(), -1 * cost)?;
+        self.database
+            .commit_reservation(reservation).await?;
         
-        // Create a log
-        let api_usage_log = APIUsage {
-            category,
-            service,
-            pii_type,
-            pii,
-            cost,
-            id:       None
-        };
-        if let Err(e) = app.database
-            .lock().await
-            .create_api_usage_log(api_usage_log, user_api_key) {
-            eprintln!("[ WARNING ]: Failed to create API usage log: {:?}", e);
+        // Create a log per lookup, spreading any remainder over the first few
+        let count = usages.len() as i32;
+        for (index, (category, service, pii_type, pii)) in usages.into_iter().enumerate() {
+            let api_usage_log = APIUsage {
+                cost:     cost / count + i32::from((index as i32) < cost % count),
+                category,
+                service,
+                pii_type,
+                pii,
+                request_id: crate::helper::request_id::current(),
+                id:       None
+            };
+            metrics().add_credits(&api_usage_log.category, &api_usage_log.service, api_usage_log.cost);
+
+            if let Err(e) = self.database
+                .create_api_usage_log(api_usage_log, user_api_key.clone()).await {
+                tracing::warn!(error = %describe(&e), "Failed to create API usage log");
+            }
         }
 
-        Ok(())
+        Ok(Receipt { cost })
     }
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum API {
     #[serde(rename = "snusbase_query")]
     SnusbaseQuery,
@@ -131,7 +228,128 @@ pub enum API {
     #[serde(rename = "sherlock")]
     Sherlock
 }
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl API {
+    pub const ALL: [API; 5] = [
+        API::SnusbaseQuery, API::SnusbaseHashing, API::SnusbaseGeolocation,
+        API::BulkVS, API::Sherlock
+    ];
+
+    pub fn as_str ( &self ) -> &'static str {
+        match self {
+            API::SnusbaseQuery       => "snusbase_query",
+            API::SnusbaseHashing     => "snusbase_hashing",
+            API::SnusbaseGeolocation => "snusbase_geolocation",
+            API::BulkVS              => "bulkvs",
+            API::Sherlock            => "sherlock"
+        }
+    }
+
+    pub fn provider ( &self ) -> Provider {
+        match self {
+            API::SnusbaseQuery | API::SnusbaseHashing | API::SnusbaseGeolocation => Provider::Snusbase,
+            API::BulkVS   => Provider::BulkVS,
+            API::Sherlock => Provider::Sherlock
+        }
+    }
+    /// The `category` this API's lookups are recorded under in usage logs.
+    pub fn category ( &self ) -> &'static str {
+        match self {
+            API::SnusbaseQuery       => "DB",
+            API::SnusbaseHashing     => "Hashing",
+            API::SnusbaseGeolocation => "Geo",
+            API::BulkVS              => "Tele",
+            API::Sherlock            => "Xref"
+        }
+    }
+
+    /// Whether this API can run wildcard lookups (billed at their own tier).
+    pub fn supports_wildcard ( &self ) -> bool {
+        matches!(self, API::SnusbaseQuery | API::SnusbaseHashing)
+    }
+
+    /// What one lookup costs.
+    pub fn cost ( &self, wildcard: bool ) -> i32 {
+        match (self, wildcard) {
+            (API::SnusbaseQuery, false)   => crate::COST_PER_DB_SNUSBASE,
+            (API::SnusbaseQuery, true)    => crate::COST_PER_DB_SNUSBASE_WILDCARD,
+            (API::SnusbaseHashing, false) => crate::COST_PER_HASHES_SNUSBASE,
+            (API::SnusbaseHashing, true)  => crate::COST_PER_HASHES_SNUSBASE_WILDCARD,
+            (API::SnusbaseGeolocation, _) => crate::COST_PER_GEO_SNUSBASE,
+            (API::BulkVS, _)              => crate::COST_PER_TELE_BULKVS,
+            (API::Sherlock, _)            => crate::COST_PER_XREF_SHERLOCK
+        }
+    }
+
+    /// The `service` a lookup is recorded under in usage logs.
+    pub fn service ( &self, wildcard: bool ) -> &'static str {
+        match (self, wildcard) {
+            (API::SnusbaseQuery, false)   => "Snusbase",
+            (API::SnusbaseQuery, true)    => "Snusbase_Wildcard",
+            (API::SnusbaseHashing, false) => "Snusbase_Hashing",
+            (API::SnusbaseHashing, true)  => "Snusbase_Hashing_Wildcard",
+            (API::SnusbaseGeolocation, _) => "Snusbase_Geolocation",
+            (API::BulkVS, _)              => "BulkVS_CNAM",
+            (API::Sherlock, _)            => "Sherlock"
+        }
+    }
+
+    /// The operation a lookup is cached under.
+    pub fn operation ( &self, wildcard: bool ) -> &'static str {
+        match (self, wildcard) {
+            (API::SnusbaseQuery, false)   => "search",
+            (API::SnusbaseQuery, true)    => "search_wildcard",
+            (API::SnusbaseHashing, false) => "hash_lookup",
+            (API::SnusbaseHashing, true)  => "hash_lookup_wildcard",
+            (API::SnusbaseGeolocation, _) => "ip_whois",
+            (API::BulkVS, _)              => "cnam",
+            (API::Sherlock, _)            => "profiles"
+        }
+    }
+
+    /// The scope an operator key needs to run this API's lookups.
+    pub fn scope ( &self ) -> Scope {
+        match self {
+            API::SnusbaseQuery       => Scope::DbQuery,
+            API::SnusbaseHashing     => Scope::HashesQuery,
+            API::SnusbaseGeolocation => Scope::GeoQuery,
+            API::BulkVS              => Scope::TeleQuery,
+            API::Sherlock            => Scope::XrefQuery
+        }
+    }
+
+    /// The PII types this provider can be queried by.
+    pub fn supported_pii ( &self ) -> &'static [PII] {
+        match self {
+            API::SnusbaseQuery       => Snusbase::SEARCH_TYPES,
+            API::SnusbaseHashing     => Snusbase::HASH_LOOKUP_TYPES,
+            API::SnusbaseGeolocation => Snusbase::GEOLOCATION_TYPES,
+            API::BulkVS              => BulkVS::LOOKUP_TYPES,
+            API::Sherlock            => Sherlock::LOOKUP_TYPES
+        }
+    }
+}
+/// An upstream service, as opposed to an `API` (one of its operations).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Provider {
+    #[serde(rename = "snusbase")]
+    Snusbase,
+    #[serde(rename = "bulkvs")]
+    BulkVS,
+    #[serde(rename = "sherlock")]
+    Sherlock
+}
+impl Provider {
+    pub const ALL: [Provider; 3] = [ Provider::Snusbase, Provider::BulkVS, Provider::Sherlock ];
+
+    pub fn as_str ( &self ) -> &'static str {
+        match self {
+            Provider::Snusbase => "snusbase",
+            Provider::BulkVS   => "bulkvs",
+            Provider::Sherlock => "sherlock"
+        }
+    }
+}
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum PII {
     #[serde(rename = "email")]
     Email,
@@ -146,19 +364,173 @@ pub enum PII {
     #[serde(rename = "name")]
     Name,
     #[serde(rename = "password")]
-    Password
+    Password,
+    #[serde(rename = "domain")]
+    Domain,
+    #[serde(rename = "address")]
+    Address,
+    #[serde(rename = "company")]
+    Company
+}
+impl PII {
+    pub fn as_str ( &self ) -> &'static str {
+        match self {
+            PII::Email    => "email",
+            PII::Phone    => "phone",
+            PII::Username => "username",
+            PII::Hash     => "hash",
+            PII::Ip       => "ip",
+            PII::Name     => "name",
+            PII::Password => "password",
+            PII::Domain   => "domain",
+            PII::Address  => "address",
+            PII::Company  => "company"
+        }
+    }
+}
+
+/// Query-string options shared by the Snusbase search routes.
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchParams {
+    /// Treat `%` and `_` in the term as wildcards. Billed at its own tier.
+    #[serde(default)]
+    pub wildcard: bool
 }
 
-pub struct AppError(anyhow::Error);
+/// Permissions an operator key can be granted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:write")]
+    UsersWrite,
+    #[serde(rename = "db:query")]
+    DbQuery,
+    #[serde(rename = "geo:query")]
+    GeoQuery,
+    #[serde(rename = "hashes:query")]
+    HashesQuery,
+    #[serde(rename = "tele:query")]
+    TeleQuery,
+    #[serde(rename = "xref:query")]
+    XrefQuery,
+    #[serde(rename = "tally:read")]
+    TallyRead,
+    #[serde(rename = "pivot:query")]
+    PivotQuery,
+    #[serde(rename = "jobs:run")]
+    JobsRun,
+    #[serde(rename = "keys:admin")]
+    KeysAdmin
+}
+impl Scope {
+    pub const ALL: [Scope; 11] = [
+        Scope::UsersRead, Scope::UsersWrite, Scope::DbQuery,
+        Scope::GeoQuery, Scope::HashesQuery, Scope::TeleQuery,
+        Scope::XrefQuery, Scope::TallyRead, Scope::PivotQuery,
+        Scope::JobsRun, Scope::KeysAdmin
+    ];
+
+    pub fn as_str ( &self ) -> &'static str {
+        match self {
+            Scope::UsersRead   => "users:read",
+            Scope::UsersWrite  => "users:write",
+            Scope::DbQuery     => "db:query",
+            Scope::GeoQuery    => "geo:query",
+            Scope::HashesQuery => "hashes:query",
+            Scope::TeleQuery   => "tele:query",
+            Scope::XrefQuery   => "xref:query",
+            Scope::TallyRead   => "tally:read",
+            Scope::PivotQuery  => "pivot:query",
+            Scope::JobsRun     => "jobs:run",
+            Scope::KeysAdmin   => "keys:admin"
+        }
+    }
+}
+impl std::str::FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str ( s: &str ) -> Result<Self> {
+        Scope::ALL.into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| anyhow!("Unknown scope `{s}`!"))
+    }
+}
+
+/// Every error a route can return. Each variant maps to one HTTP status
+///  and one stable, machine-readable `code`.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(anyhow::Error),
+    Unauthorized(anyhow::Error),
+    PaymentRequired(anyhow::Error),
+    Forbidden(anyhow::Error),
+    NotFound(anyhow::Error),
+    Conflict(anyhow::Error),
+    InvalidPII(anyhow::Error),
+    InvalidUsername(anyhow::Error),
+    Upstream(anyhow::Error),
+    Unavailable(anyhow::Error),
+    UpstreamTimeout(anyhow::Error),
+    Internal(anyhow::Error)
+}
+impl AppError {
+    pub fn status ( &self ) -> StatusCode {
+        match self {
+            Self::BadRequest(_)      => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_)    => StatusCode::UNAUTHORIZED,
+            Self::PaymentRequired(_) => StatusCode::PAYMENT_REQUIRED,
+            Self::Forbidden(_)       => StatusCode::FORBIDDEN,
+            Self::NotFound(_)        => StatusCode::NOT_FOUND,
+            Self::Conflict(_)        => StatusCode::CONFLICT,
+            Self::InvalidPII(_)      => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::InvalidUsername(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Upstream(_)        => StatusCode::BAD_GATEWAY,
+            Self::Unavailable(_)     => StatusCode::SERVICE_UNAVAILABLE,
+            Self::UpstreamTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Internal(_)        => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+    pub fn code ( &self ) -> &'static str {
+        match self {
+            Self::BadRequest(_)      => "bad_request",
+            Self::Unauthorized(_)    => "unauthorized",
+            Self::PaymentRequired(_) => "insufficient_balance",
+            Self::Forbidden(_)       => "forbidden",
+            Self::NotFound(_)        => "not_found",
+            Self::Conflict(_)        => "conflict",
+            Self::InvalidPII(_)      => "invalid_pii_type",
+            Self::InvalidUsername(_) => "invalid_username",
+            Self::Upstream(_)        => "upstream_failure",
+            Self::Unavailable(_)     => "provider_unavailable",
+            Self::UpstreamTimeout(_) => "upstream_timeout",
+            Self::Internal(_)        => "internal_error"
+        }
+    }
+    pub fn error ( &self ) -> &anyhow::Error {
+        match self {
+            Self::BadRequest(e) | Self::Unauthorized(e) | Self::PaymentRequired(e)
+                | Self::Forbidden(e) | Self::NotFound(e) | Self::Conflict(e) | Self::InvalidPII(e)
+                | Self::InvalidUsername(e) | Self::Upstream(e) | Self::Unavailable(e) | Self::UpstreamTimeout(e) | Self::Internal(e) => e
+        }
+    }
+}
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let mut headers = HeaderMap::new();
-        headers.insert("Content-Type", "application/json".parse().unwrap());
+        let status = self.status();
+        let request_id = crate::helper::request_id::current();
+
+        if status.is_server_error() {
+            tracing::error!(status = status.as_u16(), code = self.code(), error = %describe(self.error()), "Request failed");
+        }
 
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            headers,
-            format!("{{\"error\": \"{}\"}}", self.0.to_string()),
+            status,
+            Json(json!({
+                "error":      self.error().to_string(),
+                "code":       self.code(),
+                "request_id": request_id
+            })),
         ).into_response()
     }
 }
@@ -166,7 +538,49 @@ impl<E> From<E> for AppError
 where
     E: Into<anyhow::Error>,
 {
+    /// Classifies an arbitrary error by the most specific cause in its chain.
     fn from(err: E) -> Self {
-        Self(err.into())
+        let err: anyhow::Error = err.into();
+
+        for cause in err.chain() {
+            if let Some(billing_error) = cause.downcast_ref::<BillingError>() {
+                return match billing_error {
+                    BillingError::UnknownUser { .. }         => Self::NotFound(err),
+                    BillingError::UserExists { .. }          => Self::Conflict(err),
+                    BillingError::InsufficientBalance { .. } => Self::PaymentRequired(err)
+                };
+            }
+            if let Some(operator_key_error) = cause.downcast_ref::<OperatorKeyError>() {
+                return match operator_key_error {
+                    OperatorKeyError::MissingScope(_)   => Self::Forbidden(err),
+                    OperatorKeyError::UnknownKey { .. } => Self::NotFound(err),
+                    _                                   => Self::Unauthorized(err)
+                };
+            }
+            if cause.is::<BreakerOpen>() {
+                return Self::Unavailable(err);
+            }
+            if cause.is::<UsernameRejection>() {
+                return Self::InvalidUsername(err);
+            }
+            if let Some(reqwest_error) = cause.downcast_ref::<reqwest::Error>() {
+                return if reqwest_error.is_timeout() {
+                    Self::UpstreamTimeout(err)
+                } else {
+                    Self::Upstream(err)
+                };
+            }
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return Self::UpstreamTimeout(err);
+            }
+            if cause.is::<tokio_tungstenite::tungstenite::Error>() {
+                return Self::Upstream(err);
+            }
+            if cause.is::<PathRejection>() || cause.is::<JsonRejection>() || cause.is::<QueryRejection>() {
+                return Self::BadRequest(err);
+            }
+        }
+
+        Self::Internal(err)
     }
 }
\ No newline at end of file
@@ -0,0 +1,182 @@
+use crate::helper::pricing::number_from_env;
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{ Result, bail };
+use serde::{ Serialize, Deserialize };
+
+
+/// How strictly a username is vetted before it's searched for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationMode {
+    /// Every rule applies.
+    Strict,
+    /// Only the rules without which a search can't work at all.
+    Lenient,
+    /// Anything that isn't empty is searched.
+    Off
+}
+impl ValidationMode {
+    pub fn as_str ( &self ) -> &'static str {
+        match self {
+            ValidationMode::Strict  => "strict",
+            ValidationMode::Lenient => "lenient",
+            ValidationMode::Off     => "off"
+        }
+    }
+}
+impl FromStr for ValidationMode {
+    type Err = anyhow::Error;
+
+    fn from_str ( value: &str ) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "strict"  => Ok(ValidationMode::Strict),
+            "lenient" => Ok(ValidationMode::Lenient),
+            "off"     => Ok(ValidationMode::Off),
+            _ => bail!("Unknown username validation mode `{value}`; expected strict, lenient or off!")
+        }
+    }
+}
+
+/// Picks the username validation mode for one request, e.g.
+///  `?validation=strict`; the deployment's default if omitted.
+#[derive(Debug, Default, Deserialize)]
+pub struct ValidationParams {
+    #[serde(default)]
+    pub validation: Option<ValidationMode>
+}
+
+/// A username rule, named so that a rejection can say which one failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsernameRule {
+    NotEmpty,
+    NoWhitespace,
+    MinLength,
+    MaxLength,
+    ForbiddenChars,
+    AlphaFirst
+}
+impl UsernameRule {
+    pub fn as_str ( &self ) -> &'static str {
+        match self {
+            UsernameRule::NotEmpty       => "not_empty",
+            UsernameRule::NoWhitespace   => "no_whitespace",
+            UsernameRule::MinLength      => "min_length",
+            UsernameRule::MaxLength      => "max_length",
+            UsernameRule::ForbiddenChars => "forbidden_chars",
+            UsernameRule::AlphaFirst     => "alpha_first"
+        }
+    }
+    /// The least strict mode the rule applies in.
+    fn mode ( &self ) -> ValidationMode {
+        match self {
+            UsernameRule::NotEmpty     => ValidationMode::Off,
+            UsernameRule::NoWhitespace => ValidationMode::Lenient,
+            _                          => ValidationMode::Strict
+        }
+    }
+}
+
+/// Why a username was refused.
+#[derive(Debug)]
+pub struct UsernameRejection {
+    pub rule:   UsernameRule,
+    pub mode:   ValidationMode,
+    pub reason: String
+}
+impl fmt::Display for UsernameRejection {
+    fn fmt ( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        write!(f, "Username fails the `{}` rule ({} validation): {}!", self.rule.as_str(), self.mode.as_str(), self.reason)
+    }
+}
+impl std::error::Error for UsernameRejection {}
+
+/// The username rules for this deployment, read once at startup.
+///
+/// Strict validation keeps to usernames Sherlock gives useful results for;
+///  each of its rules can be tuned with `SHERLOCK_USERNAME_*` variables, and
+///  `SHERLOCK_USERNAME_VALIDATION` sets the mode used when a request doesn't
+///  pick one.
+#[derive(Debug, Clone)]
+pub struct UsernamePolicy {
+    pub mode:            ValidationMode,
+    pub min_length:      usize,
+    pub max_length:      usize,
+    pub forbidden_chars: Vec<char>,
+    pub alpha_first:     bool
+}
+impl UsernamePolicy {
+    pub fn from_env () -> Result<Self> {
+        let mode = match std::env::var("SHERLOCK_USERNAME_VALIDATION") {
+            Ok(mode) => mode.parse()?,
+            Err(_) => ValidationMode::Lenient
+        };
+
+        let min_length = number_from_env("SHERLOCK_USERNAME_MIN_LENGTH", 1)?;
+        let max_length = number_from_env("SHERLOCK_USERNAME_MAX_LENGTH", 19)?;
+        if min_length == 0 || max_length < min_length {
+            bail!("SHERLOCK_USERNAME_MIN_LENGTH must be at least 1 and at most SHERLOCK_USERNAME_MAX_LENGTH!");
+        }
+
+        let forbidden_chars = std::env::var("SHERLOCK_USERNAME_FORBIDDEN_CHARS")
+            .unwrap_or_else(|_| " .-_#".to_string())
+            .chars()
+            .collect();
+
+        let alpha_first = match std::env::var("SHERLOCK_USERNAME_ALPHA_FIRST") {
+            Ok(value) => match value.trim().to_lowercase().as_str() {
+                "true" | "1"  => true,
+                "false" | "0" => false,
+                _ => bail!("SHERLOCK_USERNAME_ALPHA_FIRST must be true or false!")
+            },
+            Err(_) => true
+        };
+
+        Ok(Self { mode, min_length, max_length, forbidden_chars, alpha_first })
+    }
+
+    /// Checks `username` against every rule `mode` (or the default mode)
+    ///  applies, returning the first one it fails.
+    pub fn check ( &self, username: &str, mode: Option<ValidationMode> ) -> Result<(), UsernameRejection> {
+        let mode = mode.unwrap_or(self.mode);
+        let applies = |rule: UsernameRule| match mode {
+            ValidationMode::Strict  => true,
+            ValidationMode::Lenient => rule.mode() != ValidationMode::Strict,
+            ValidationMode::Off     => rule.mode() == ValidationMode::Off
+        };
+        let reject = |rule: UsernameRule, reason: String| Err(UsernameRejection { rule, mode, reason });
+
+        let length = username.chars().count();
+
+        if username.trim().is_empty() {
+            return reject(UsernameRule::NotEmpty, "it's empty".to_string());
+        }
+        if applies(UsernameRule::NoWhitespace) && username.chars().any(|ch| ch.is_whitespace() || ch.is_control()) {
+            return reject(UsernameRule::NoWhitespace, "it contains whitespace or control characters".to_string());
+        }
+        if applies(UsernameRule::MinLength) && length < self.min_length {
+            return reject(UsernameRule::MinLength, format!(
+                "it's {length} characters, under the minimum of {}", self.min_length
+            ));
+        }
+        if applies(UsernameRule::MaxLength) && length > self.max_length {
+            return reject(UsernameRule::MaxLength, format!(
+                "it's {length} characters, over the maximum of {}", self.max_length
+            ));
+        }
+        if applies(UsernameRule::ForbiddenChars) {
+            if let Some(ch) = username.chars().find(|ch| self.forbidden_chars.contains(ch)) {
+                return reject(UsernameRule::ForbiddenChars, format!("it contains `{ch}`"));
+            }
+        }
+        if applies(UsernameRule::AlphaFirst) && self.alpha_first
+            && !username.chars().next().is_some_and(char::is_alphabetic)
+        {
+            return reject(UsernameRule::AlphaFirst, "it doesn't start with a letter".to_string());
+        }
+
+        Ok(())
+    }
+}
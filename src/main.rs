@@ -1,96 +1,185 @@
-mod apis;
-mod routes;
-mod helper;
-
-pub const COST_PER_DB_SNUSBASE:     i32 = 30;
-pub const COST_PER_GEO_SNUSBASE:    i32 = 15;
-pub const COST_PER_XREF_SHERLOCK:   i32 = 10;
-pub const COST_PER_TELE_BULKVS:     i32 = 50;
-pub const COST_PER_HASHES_SNUSBASE: i32 = 15;
-
-
-use crate::apis::{
-    Snusbase,
-    Sherlock,
-    BulkVS,
-    NocoDB
-};
-use crate::helper::types::AppState;
-
-use std::sync::Arc;
-use axum::{
-    routing::post, 
-    Router
-};
-use tokio::sync::Mutex;
-use anyhow::{ Result, anyhow, Context };
-
-
-#[tokio::main]
-async fn main() -> Result<()> {
-
-    // Build each microservice
-    let app_state = AppState {
-        sherlock: Arc::new(Mutex::new(Sherlock::new()?)),
-        snusbase: Arc::new(Mutex::new(Snusbase::new()?)),
-        bulkvs:   Arc::new(Mutex::new(BulkVS::new()?)),
-        database: Arc::new(Mutex::new(NocoDB::new()?))
-    };
-
-    // Verify the database connection
-    app_state.database
-        .lock().await.verify_db()
-        .context("Failed to verify database connection!")?;
-    
-    // Build each route set
-    let tele_routes = Router::new()
-        .route( "/bulkvs_cnam", post(crate::routes::tele::bulkvs_cnam::bulkvs_cnam) );
-
-    let xref_routes = Router::new()
-        .route( "/sherlock", post(crate::routes::xref::sherlock::sherlock) );
-    
-    let geo_routes = Router::new()
-        .route( "/snusbase", post(crate::routes::geo::snusbase::snusbase_geo) );
-    
-    let hashes_routes = Router::new()
-        .route( "/snusbase/:pii_type", post(crate::routes::hashes::snusbase::snusbase_hashing) );
-    
-    let tally_routes = Router::new()
-        .route( "/:target_api/:pii_type", post(crate::routes::tally_api) );
-    
-    let nocodb_routes = Router::new()
-        .route("/get",    post(crate::routes::nocodb::get_user       ) )
-        .route("/create", post(crate::routes::nocodb::create_user    ) )
-        .route("/fund",   post(crate::routes::nocodb::offset_balance ) );
-    
-    let db_routes = Router::new()
-        .route("/snusbase/:pii_type", post(crate::routes::db::snusbase::snusbase_query) );
-
-    // Build the API routes
-    let api_v1 = Router::new()
-        .nest("/tally", tally_routes)
-        .nest("/users", nocodb_routes)
-        .nest("/tele", tele_routes)
-        .nest("/xref", xref_routes)
-        .nest("/geo", geo_routes)
-        .nest("/hashes", hashes_routes)
-        .nest("/db", db_routes)
-        .with_state(app_state);
-
-    let app = Router::new()
-        .nest("/api/v1", api_v1);
-
-    let port = std::env::var("PORT")
-        .context("Missing PORT env variable!")?;
-    let address = format!("0.0.0.0:{port}");
-
-    println!("Listening on {port}, address {address}...");
-    let listener = tokio::net::TcpListener::bind(&address).await
-        .context("Failed to bind to address!")?;
-
-    axum::serve(listener, app).await
-        .map_err(|e| anyhow!("{:?}", e))
-        .context("Error in core server, terminating...")?;
-
-    Ok(())
+#![allow(clippy::upper_case_acronyms)]
+
+mod apis;
+mod routes;
+mod helper;
+
+pub const COST_PER_DB_SNUSBASE:     i32 = 30;
+pub const COST_PER_GEO_SNUSBASE:    i32 = 15;
+pub const COST_PER_XREF_SHERLOCK:   i32 = 10;
+pub const COST_PER_TELE_BULKVS:     i32 = 50;
+pub const COST_PER_HASHES_SNUSBASE: i32 = 15;
+
+pub const COST_PER_DB_SNUSBASE_WILDCARD:     i32 = 60;
+pub const COST_PER_HASHES_SNUSBASE_WILDCARD: i32 = 30;
+
+
+use crate::apis::{
+    Snusbase,
+    Sherlock,
+    BulkVS,
+    Billing,
+    Operators,
+    ResponseCache,
+    Jobs,
+    HttpClients
+};
+use crate::helper::types::{ AppState, Scope };
+use crate::helper::auth;
+use crate::helper::pricing::Pricing;
+use crate::helper::fields::FieldSchema;
+use crate::apis::health::describe;
+
+use std::sync::Arc;
+use axum::{
+    routing::{ get, post },
+    middleware::from_fn_with_state,
+    Router
+};
+use anyhow::{ Result, anyhow, Context };
+
+
+#[tokio::main]
+async fn main() -> Result<()> {
+
+    // Log before anything else, so startup problems are logged too
+    crate::helper::logging::init()?;
+
+    // Build the pooled HTTP clients shared by every adapter
+    let clients = HttpClients::new()
+        .context("Failed to build HTTP clients!")?;
+
+    // Users, balances and operator keys share one store
+    let store = crate::apis::billing::store_from_env(&clients)?;
+
+    // Build each microservice
+    let app_state = AppState {
+        sherlock: Arc::new(Sherlock::new()?),
+        snusbase: Arc::new(Snusbase::new(&clients)?),
+        bulkvs:   Arc::new(BulkVS::new(&clients)?),
+        database:  Arc::new(Billing::new(store.clone())),
+        operators: Arc::new(Operators::new(store)?),
+        pricing:   Arc::new(Pricing::from_env()?),
+        cache:     Arc::new(ResponseCache::new()?),
+        fields:    Arc::new(FieldSchema::from_env()?),
+        jobs:      Arc::new(Jobs::new()?),
+        clients
+    };
+
+    // Verify the database connection
+    app_state.database
+        .verify_db().await
+        .context("Failed to verify database connection!")?;
+
+    // Check on Sherlock without waiting; the rest of the API works without it
+    tokio::spawn({
+        let sherlock = app_state.sherlock.clone();
+
+        async move {
+            if let Err(e) = sherlock.probe().await {
+                tracing::warn!(error = %describe(&e), "Sherlock is unreachable, its lookups will fail until it's back");
+            }
+        }
+    });
+    
+    // Build each route set. Every group is authenticated by an operator
+    //  key with the group's scope; paid groups also bill a user.
+    let operator = |scope: Scope| from_fn_with_state((app_state.clone(), scope), auth::operator_auth);
+    let billable = || from_fn_with_state(app_state.clone(), auth::billable_user);
+
+    let tele_routes = Router::new()
+        .route( "/bulkvs_cnam", post(crate::routes::tele::bulkvs_cnam::bulkvs_cnam) )
+        .route_layer(billable())
+        .route_layer(operator(Scope::TeleQuery));
+
+    let xref_routes = Router::new()
+        .route( "/sherlock", post(crate::routes::xref::sherlock::sherlock) )
+        .route( "/sherlock/stream", get(crate::routes::xref::sherlock::sherlock_stream) )
+        .route_layer(billable())
+        .route_layer(operator(Scope::XrefQuery));
+    
+    let geo_routes = Router::new()
+        .route( "/snusbase", post(crate::routes::geo::snusbase::snusbase_geo) )
+        .route_layer(billable())
+        .route_layer(operator(Scope::GeoQuery));
+    
+    let hashes_routes = Router::new()
+        .route( "/snusbase/:pii_type", post(crate::routes::hashes::snusbase::snusbase_hashing) )
+        .route_layer(billable())
+        .route_layer(operator(Scope::HashesQuery));
+    
+    let tally_routes = Router::new()
+        .route( "/:target_api/:pii_type", post(crate::routes::tally_api) )
+        .route_layer(billable())
+        .route_layer(operator(Scope::TallyRead));
+    
+    let pivot_routes = Router::new()
+        .route( "/pivot", post(crate::routes::pivot::pivot) )
+        .route_layer(billable())
+        .route_layer(operator(Scope::PivotQuery));
+    
+    // Submitting a job bills a user; checking on one only needs their key
+    let jobs_routes = Router::new()
+        .route("/jobs",            post(crate::routes::jobs::submit_job ).route_layer(billable()) )
+        .route("/jobs/:id",        get(crate::routes::jobs::get_job     ) )
+        .route("/jobs/:id/result", get(crate::routes::jobs::job_result  ) )
+        .route("/jobs/:id/cancel", post(crate::routes::jobs::cancel_job ) )
+        .route_layer(operator(Scope::JobsRun));
+    
+    let nocodb_routes = Router::new()
+        .route("/get",    post(crate::routes::nocodb::get_user       ).route_layer(operator(Scope::UsersRead )) )
+        .route("/create", post(crate::routes::nocodb::create_user    ).route_layer(operator(Scope::UsersWrite)) )
+        .route("/fund",   post(crate::routes::nocodb::offset_balance ).route_layer(operator(Scope::UsersWrite)) );
+    
+    let keys_routes = Router::new()
+        .route("/list",   post(crate::routes::keys::list_keys  ) )
+        .route("/create", post(crate::routes::keys::create_key ) )
+        .route("/revoke", post(crate::routes::keys::revoke_key ) )
+        .route_layer(operator(Scope::KeysAdmin));
+    
+    let db_routes = Router::new()
+        .route("/snusbase/batch",     post(crate::routes::db::snusbase::snusbase_batch_query) )
+        .route("/snusbase/:pii_type", post(crate::routes::db::snusbase::snusbase_query) )
+        .route_layer(billable())
+        .route_layer(operator(Scope::DbQuery));
+
+    // Build the API routes
+    let api_v1 = Router::new()
+        .route("/capabilities", get(crate::routes::capabilities::capabilities))
+        .route("/status", get(crate::routes::health::status))
+        .nest("/tally", tally_routes)
+        .nest("/users", nocodb_routes)
+        .nest("/keys", keys_routes)
+        .nest("/tele", tele_routes)
+        .nest("/xref", xref_routes)
+        .nest("/geo", geo_routes)
+        .nest("/hashes", hashes_routes)
+        .nest("/db", db_routes)
+        .merge(pivot_routes)
+        .merge(jobs_routes)
+        .with_state(app_state.clone());
+
+    // Probes for the container orchestrator live outside the versioned API
+    let app = Router::new()
+        .route("/healthz", get(crate::routes::health::healthz))
+        .route("/readyz", get(crate::routes::health::readyz))
+        .route("/metrics", get(crate::routes::metrics::metrics))
+        .nest("/api/v1", api_v1)
+        .with_state(app_state)
+        .layer(axum::middleware::from_fn(crate::helper::metrics::track_requests))
+        .layer(axum::middleware::from_fn(crate::helper::request_id::request_id));
+
+    let port = std::env::var("PORT")
+        .context("Missing PORT env variable!")?;
+    let address = format!("0.0.0.0:{port}");
+
+    tracing::info!(port = %port, address = %address, "Listening");
+    let listener = tokio::net::TcpListener::bind(&address).await
+        .context("Failed to bind to address!")?;
+
+    axum::serve(listener, app).await
+        .map_err(|e| anyhow!("{:?}", e))
+        .context("Error in core server, terminating...")?;
+
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,118 @@
+use crate::helper::types::{ API, AppState, PII, Scope };
+use crate::routes::pivot::EntityKind;
+
+use axum::{
+    extract::State,
+    Json
+};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct RouteCapability {
+    method:    &'static str,
+    path:      String,
+    scope:     Scope,
+    // Set on /jobs and /pivot, which take the API to run as part of the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api:       Option<API>,
+    pii_types: Vec<PII>,
+    cost:      i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wildcard_cost: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_terms:     Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_depth:     Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_entities:  Option<usize>
+}
+impl RouteCapability {
+    fn post ( path: &str, scope: Scope, pii_types: &[PII], cost: i32 ) -> Self {
+        Self {
+            method:        "POST",
+            path:          format!("/api/v1{path}"),
+            scope,
+            api:           None,
+            pii_types:     pii_types.to_vec(),
+            cost,
+            wildcard_cost: None,
+            max_terms:     None,
+            max_depth:     None,
+            max_entities:  None
+        }
+    }
+    /// A route that runs `api`'s lookups directly, billed as that API.
+    fn lookup ( path: &str, api: API ) -> Self {
+        Self {
+            wildcard_cost: api.supports_wildcard().then(|| api.cost(true)),
+            ..Self::post(path, api.scope(), api.supported_pii(), api.cost(false))
+        }
+    }
+}
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    routes: Vec<RouteCapability>
+}
+
+/// Which PII types every lookup route accepts, and what it costs, so
+///  clients don't have to hardcode the matrix.
+pub async fn capabilities (
+    State(app): State<AppState>
+) -> Json<Capabilities> {
+    let mut routes = vec!(
+        RouteCapability::lookup("/db/snusbase/:pii_type", API::SnusbaseQuery),
+        RouteCapability {
+            max_terms:     Some(app.pricing.batch_max_terms),
+            wildcard_cost: None,
+            ..RouteCapability::lookup("/db/snusbase/batch", API::SnusbaseQuery)
+        },
+        RouteCapability::lookup("/hashes/snusbase/:pii_type", API::SnusbaseHashing),
+        RouteCapability::lookup("/geo/snusbase", API::SnusbaseGeolocation),
+        RouteCapability::lookup("/tele/bulkvs_cnam", API::BulkVS),
+        RouteCapability::lookup("/xref/sherlock", API::Sherlock),
+        RouteCapability {
+            method: "GET",
+            ..RouteCapability::lookup("/xref/sherlock/stream", API::Sherlock)
+        }
+    );
+
+    // A pivot is billed per lookup, so list what each API it expands
+    //  through costs, and which seed types reach it
+    for api in API::ALL {
+        let seeds: Vec<PII> = api.supported_pii().iter()
+            .filter(|pii| EntityKind::from_pii(pii)
+                .is_some_and(|kind| kind.expands_through().contains(&api)))
+            .cloned()
+            .collect();
+        if seeds.is_empty() {
+            continue;
+        }
+
+        routes.push(RouteCapability {
+            api:          Some(api),
+            max_depth:    Some(app.pricing.pivot_max_depth),
+            max_entities: Some(app.pricing.pivot_max_entities),
+            ..RouteCapability::post("/pivot", Scope::PivotQuery, &seeds, api.cost(false))
+        });
+    }
+
+    // Jobs also need the scope of the API they run
+    for api in API::ALL {
+        routes.push(RouteCapability {
+            api:   Some(api),
+            scope: Scope::JobsRun,
+            ..RouteCapability::lookup("/jobs", api)
+        });
+    }
+
+    for api in API::ALL {
+        routes.push(RouteCapability::post(
+            &format!("/tally/{}/:pii_type", api.as_str()),
+            Scope::TallyRead,
+            api.supported_pii(),
+            app.pricing.preview_price(api.provider())
+        ));
+    }
+
+    Json(Capabilities { routes })
+}
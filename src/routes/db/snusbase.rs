@@ -1,68 +1,232 @@
-use crate::helper::types::{ AppState, AppError, PII };
+use crate::helper::extract::{ Path, Query };
+use crate::helper::types::{ AppState, AppError, PII, API, SearchParams };
+use crate::helper::cache::{ CacheInfo, ForceFresh };
+use crate::helper::request_id;
+use crate::helper::findings::{ FormatParams, Formatted };
+use crate::helper::auth::{ BillableUser, Receipt };
+use crate::apis::Snusbase;
 use crate::apis::snusbase::SnusbaseDBResponse;
+use crate::apis::cache::CacheKey;
+
+use std::collections::{ BTreeMap, HashSet };
 
 use axum::{
-    http::header::HeaderMap,
-    extract::{State, Path},
+    extract::{ State, rejection::JsonRejection },
     Json
 };
 use anyhow::{ Result, anyhow };
+use serde::{ Serialize, Deserialize };
+use tokio::task::JoinSet;
+
+#[derive(Debug, Deserialize)]
+pub struct BatchTerm {
+    pii_type: PII,
+    term:     String
+}
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    terms: Vec<BatchTerm>
+}
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchResult {
+    Ok {
+        cached: bool,
+        result: Formatted<SnusbaseDBResponse>
+    },
+    Error {
+        code:   &'static str,
+        error:  String
+    }
+}
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    succeeded: usize,
+    failed:    usize,
+    cost:      i32,
+    // By PII type, then term, so one term can be searched as several types
+    results:   BTreeMap<&'static str, BTreeMap<String, BatchResult>>
+}
 
-pub async fn snusbase_query ( 
+fn is_supported ( pii_type: &PII ) -> bool {
+    Snusbase::SEARCH_TYPES.contains(pii_type)
+}
+
+pub async fn snusbase_query (
     State(app): State<AppState>,
     Path(pii_type): Path<PII>,
-    headers: HeaderMap,
+    Query(params): Query<SearchParams>,
+    Query(FormatParams { format }): Query<FormatParams>,
+    user: BillableUser,
+    fresh: ForceFresh,
     pii: String
-) -> Result<Json<SnusbaseDBResponse>, AppError> {
-    // Verify the API key
-    app.verify_api_key_header(&headers)?;
+) -> Result<(Receipt, CacheInfo, Json<Formatted<SnusbaseDBResponse>>), AppError> {
+    if !is_supported(&pii_type) {
+        return Err(AppError::InvalidPII(anyhow!("Invalid PII type for Snusbase Query API!")));
+    }
 
-    let cost = crate::COST_PER_DB_SNUSBASE;
+    // Wildcard searches are validated and billed at their own tier
+    let (api, wildcard) = (API::SnusbaseQuery, params.wildcard);
+    if wildcard {
+        app.snusbase
+            .validate_wildcard(&pii)
+            .map_err(AppError::BadRequest)?;
+    }
 
-    // Verify the user has enough balance
-    app.verify_user_api_key_has_balance(
-        &app,
-        &headers, 
-        cost
+    // Query Snusbase (or the cache), billing the user
+    let (receipt, cache, res) = app.billed_lookup(
+        &user,
+        CacheKey::new(api.provider(), api.operation(wildcard), &pii_type, &pii),
+        fresh,
+        api.cost(wildcard),
+        (api.category().to_string(), api.service(wildcard).to_string(), pii_type.clone(), pii.clone()),
+        async {
+            Ok(app.snusbase
+                .get_by(&pii_type, pii.clone(), params.wildcard)
+                .await?)
+        }
     ).await?;
 
-    // Query Snusbase
-    let res = match pii_type {
-        PII::Email => app.snusbase.lock()
-                .await
-                .get_by_email(pii.clone())
-                .await?,
-        PII::Username => app.snusbase.lock()
-                .await
-                .get_by_username(pii.clone())
-                .await?,
-        PII::Hash => app.snusbase.lock()
-                .await
-                .get_by_hash(pii.clone())
-                .await?,
-        PII::Ip => app.snusbase.lock()
-                .await
-                .get_by_last_ip(pii.clone())
-                .await?,
-        PII::Name => app.snusbase.lock()
-                .await
-                .get_by_name(pii.clone())
-                .await?,
-        PII::Password => app.snusbase.lock()
-                .await
-                .get_by_password(pii.clone())
-                .await?,
-        _ => {
-            return Err(anyhow!("Invalid PII type for Snusbase Query API!"))?;
+    Ok((receipt, cache, Json(format.apply(res, &app.fields))))
+}
+pub async fn snusbase_batch_query (
+    State(app): State<AppState>,
+    Query(FormatParams { format }): Query<FormatParams>,
+    user: BillableUser,
+    fresh: ForceFresh,
+    request: Result<Json<BatchRequest>, JsonRejection>
+) -> Result<(Receipt, Json<BatchResponse>), AppError> {
+    let Json(request) = request?;
+
+    // Validate the whole batch before reserving anything
+    if request.terms.is_empty() {
+        return Err(AppError::BadRequest(anyhow!("Batch must contain at least one term!")));
+    }
+    if request.terms.len() > app.pricing.batch_max_terms {
+        return Err(AppError::BadRequest(anyhow!(
+            "Batch of {} terms exceeds the limit of {}!",
+            request.terms.len(), app.pricing.batch_max_terms
+        )));
+    }
+    let mut seen = HashSet::new();
+    for BatchTerm { pii_type, term } in &request.terms {
+        if term.trim().is_empty() {
+            return Err(AppError::BadRequest(anyhow!("Batch terms must not be empty!")));
         }
-    };
+        if !seen.insert((pii_type, term.as_str())) {
+            return Err(AppError::BadRequest(anyhow!(
+                "Term `{term}` appears more than once as `{}`!", pii_type.as_str()
+            )));
+        }
+        if !is_supported(pii_type) {
+            return Err(AppError::InvalidPII(anyhow!("Invalid PII type for Snusbase Query API!")));
+        }
+    }
+
+    let api = API::SnusbaseQuery;
+    let per_term = api.cost(false);
+
+    // Serve what the cache already has, as the single-term route would
+    let mut hits = Vec::new();
+    let mut misses = Vec::new();
+    for BatchTerm { pii_type, term } in request.terms {
+        let key = CacheKey::new(api.provider(), api.operation(false), &pii_type, &term);
+        let hit = match fresh {
+            ForceFresh(true) => None,
+            ForceFresh(false) => app.cache.get::<SnusbaseDBResponse>(&key).await
+        };
+
+        match hit {
+            Some(hit) => hits.push((pii_type, term, hit.value)),
+            None => misses.push((pii_type, term, key))
+        }
+    }
+
+    if !misses.is_empty() {
+        app.snusbase.resilience.breaker.check()?;
+    }
 
-    // Deduct the cost from the user's balance
-    app.deduct_cost_and_log(
+    // Hits are billed at the cache-hit price; misses as a batch, and only
+    //  for the terms that succeed
+    let hit_reservation = user.reserve(
         &app,
-        &headers, 
-        ("DB".to_string(), "Snusbase".to_string(), pii_type, pii, cost),
+        app.pricing.cache_hit_cost(per_term) * hits.len() as i32
     ).await?;
+    let mut reservation = user.reserve(
+        &app,
+        app.pricing.batch_cost(per_term, misses.len())
+    ).await?;
+
+    // Query each term separately so results and failures stay attributable.
+    //  Snusbase's own limiter bounds how many are in flight at once.
+    let mut queries = JoinSet::new();
+    for (pii_type, term, key) in misses {
+        let app = app.clone();
+
+        queries.spawn(request_id::propagate(async move {
+            let res = app.snusbase
+                .get_by(&pii_type, term.clone(), false).await
+                .map_err(AppError::from);
+            if let Ok(res) = &res {
+                app.cache.put(&key, res).await;
+            }
+
+            (pii_type, term, res)
+        }));
+    }
+
+    let mut results: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+    let (mut succeeded, mut failed) = (hits.len(), 0);
+    let mut hit_usages = Vec::new();
+    for (pii_type, term, result) in hits {
+        hit_usages.push((api.category().to_string(), format!("{}_Cached", api.service(false)), pii_type.clone(), term.clone()));
+
+        results.entry(pii_type.as_str()).or_default().insert(term, BatchResult::Ok {
+            cached: true,
+            result: format.apply(result, &app.fields)
+        });
+    }
+
+    let mut usages = Vec::new();
+    while let Some(joined) = queries.join_next().await {
+        let (pii_type, term, res) = joined
+            .map_err(|e| AppError::Internal(anyhow!("Batch query task failed: {e}")))?;
+
+        let result = match res {
+            Ok(result) => {
+                usages.push((api.category().to_string(), api.service(false).to_string(), pii_type.clone(), term.clone()));
+
+                succeeded += 1;
+
+                BatchResult::Ok {
+                    cached: false,
+                    result: format.apply(result, &app.fields)
+                }
+            },
+            Err(e) => {
+                failed += 1;
+
+                BatchResult::Error {
+                    code:  e.code(),
+                    error: e.error().to_string()
+                }
+            }
+        };
+
+        results.entry(pii_type.as_str()).or_default().insert(term, result);
+    }
+
+    // Commit only the succeeded terms, discounted as a batch of that size
+    reservation.reduce_to(app.pricing.batch_cost(per_term, usages.len()));
+    let receipt = Receipt {
+        cost: app.commit_costs_and_log(hit_reservation, hit_usages).await?.cost
+            + app.commit_costs_and_log(reservation, usages).await?.cost
+    };
 
-    Ok(Json(res))
-}
\ No newline at end of file
+    Ok((receipt, Json(BatchResponse {
+        succeeded,
+        failed,
+        cost:    receipt.cost,
+        results
+    })))
+}
@@ -1,8 +1,12 @@
-use crate::helper::types::{ AppState, AppError, PII };
+use crate::helper::extract::Query;
+use crate::helper::types::{ AppState, AppError, PII, API };
+use crate::helper::auth::{ BillableUser, Receipt };
+use crate::helper::cache::{ CacheInfo, ForceFresh };
+use crate::helper::findings::{ FormatParams, Formatted };
 use crate::apis::snusbase::SnusbaseIPResponse;
+use crate::apis::cache::CacheKey;
 
 use axum::{
-    http::header::HeaderMap,
     extract::State,
     Json
 };
@@ -10,33 +14,26 @@ use anyhow::{ Result, Context };
 
 pub async fn snusbase_geo ( 
     State(app): State<AppState>,
-    headers: HeaderMap,
+    Query(FormatParams { format }): Query<FormatParams>,
+    user: BillableUser,
+    fresh: ForceFresh,
     ip: String
-) -> Result<Json<SnusbaseIPResponse>, AppError> {
-    // Verify the API key
-    app.verify_api_key_header(&headers)?;
+) -> Result<(Receipt, CacheInfo, Json<Formatted<SnusbaseIPResponse>>), AppError> {
+    let api = API::SnusbaseGeolocation;
 
-    let cost = crate::COST_PER_GEO_SNUSBASE;
-
-    // Verify the user has enough balance
-    app.verify_user_api_key_has_balance(
-        &app,
-        &headers, 
-        cost
-    ).await?;
-
-    // Get the response from BulkVS
-    let response = app.snusbase
-        .lock().await
-        .whois_ip_query(vec!(ip.clone())).await
-        .context("Failed to get Geolocation results from Snusbase!")?;
-
-    // Deduct the cost from the user's balance
-    app.deduct_cost_and_log(
-        &app,
-        &headers, 
-        ("Geo".to_string(), "Snusbase".to_string(), PII::Ip, ip, cost),
+    // Query Snusbase (or the cache), billing the user
+    let (receipt, cache, response) = app.billed_lookup(
+        &user,
+        CacheKey::new(api.provider(), api.operation(false), &PII::Ip, &ip),
+        fresh,
+        api.cost(false),
+        (api.category().to_string(), api.service(false).to_string(), PII::Ip, ip.clone()),
+        async {
+            Ok(app.snusbase
+                .whois_ip_query(vec!(ip.clone())).await
+                .context("Failed to get Geolocation results from Snusbase!")?)
+        }
     ).await?;
 
-    Ok(Json(response))
-}
\ No newline at end of file
+    Ok((receipt, cache, Json(format.apply(response, &app.fields))))
+}
@@ -1,9 +1,14 @@
-use crate::helper::types::{ AppState, AppError, PII };
+use crate::helper::extract::{ Path, Query };
+use crate::helper::types::{ AppState, AppError, PII, API, SearchParams };
+use crate::helper::auth::{ BillableUser, Receipt };
+use crate::helper::cache::{ CacheInfo, ForceFresh };
+use crate::helper::findings::{ FormatParams, Formatted };
+use crate::apis::Snusbase;
 use crate::apis::snusbase::SnusbaseHashLookupResponse;
+use crate::apis::cache::CacheKey;
 
 use axum::{
-    http::header::HeaderMap,
-    extract::{State, Path},
+    extract::State,
     Json
 };
 use anyhow::{ Result, anyhow, Context };
@@ -11,44 +16,46 @@ use anyhow::{ Result, anyhow, Context };
 pub async fn snusbase_hashing ( 
     State(app): State<AppState>,
     Path(pii_type): Path<PII>,
-    headers: HeaderMap,
+    Query(params): Query<SearchParams>,
+    Query(FormatParams { format }): Query<FormatParams>,
+    user: BillableUser,
+    fresh: ForceFresh,
     pii: String
-) -> Result<Json<SnusbaseHashLookupResponse>, AppError> {
-    // Verify the API key
-    app.verify_api_key_header(&headers)?;
+) -> Result<(Receipt, CacheInfo, Json<Formatted<SnusbaseHashLookupResponse>>), AppError> {
+    if !Snusbase::HASH_LOOKUP_TYPES.contains(&pii_type) {
+        return Err(AppError::InvalidPII(anyhow!("Invalid PII type for Snusbase Hashing API!")));
+    }
 
-    let cost = crate::COST_PER_HASHES_SNUSBASE;
+    // Wildcard lookups are validated and billed at their own tier
+    let (api, wildcard) = (API::SnusbaseHashing, params.wildcard);
+    if wildcard {
+        app.snusbase
+            .validate_wildcard(&pii)
+            .map_err(AppError::BadRequest)?;
+    }
 
-    // Verify the user has enough balance
-    app.verify_user_api_key_has_balance(
-        &app,
-        &headers, 
-        cost
+    // Query Snusbase (or the cache), billing the user
+    let (receipt, cache, response) = app.billed_lookup(
+        &user,
+        CacheKey::new(api.provider(), api.operation(wildcard), &pii_type, &pii),
+        fresh,
+        api.cost(wildcard),
+        (api.category().to_string(), api.service(wildcard).to_string(), pii_type.clone(), pii.clone()),
+        async {
+            Ok(match pii_type {
+                PII::Password => {
+                    app.snusbase
+                        .rehash(pii.clone(), params.wildcard)
+                        .await
+                },
+                _ => {
+                    app.snusbase
+                        .dehash(pii.clone(), params.wildcard)
+                        .await
+                }
+            }.context("Failed to get Hashing results from Snusbase!")?)
+        }
     ).await?;
 
-    // Query Snusbase
-    let response = match pii_type {
-        PII::Password => {
-            app.snusbase
-                .lock().await
-                .rehash(pii.clone())
-                .await
-        },
-        PII::Hash => {
-            app.snusbase
-                .lock().await
-                .dehash(pii.clone())
-                .await
-        },
-        _ => Err(anyhow!("Invalid PII type!").into())
-    }.context("Failed to get Hashing results from Snusbase!")?;
-
-    // Deduct the cost from the user's balance
-    app.deduct_cost_and_log(
-        &app,
-        &headers, 
-        ("Hashing".to_string(), "Snusbase".to_string(), pii_type, pii, cost),
-    ).await?;
-
-    Ok(Json(response))
-}
\ No newline at end of file
+    Ok((receipt, cache, Json(format.apply(response, &app.fields))))
+}
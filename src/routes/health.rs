@@ -0,0 +1,128 @@
+use crate::helper::types::{ AppState, Provider };
+use crate::apis::health::{ HealthState, HealthStatus, describe };
+use crate::apis::resilience::BreakerState;
+
+use std::future::Future;
+use std::time::{ Duration, Instant };
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    Json
+};
+use anyhow::{ Result, Context, anyhow };
+use serde::Serialize;
+use serde_json::{ json, Value };
+
+/// How long each readiness check may take.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Liveness: the process is up and serving requests. Deliberately checks
+///  nothing else, so a dependency outage doesn't get the API restarted.
+pub async fn healthz () -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    name:       &'static str,
+    /// Whether the API is unready without it.
+    required:   bool,
+    ok:         bool,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error:      Option<String>
+}
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    ready:  bool,
+    checks: Vec<Check>
+}
+
+/// Readiness: whether the dependencies every paid route needs (the
+///  billing store and the outbound proxy) are reachable; 503 if not.
+///  Sherlock is checked too, but only one route group needs it.
+pub async fn readyz (
+    State(app): State<AppState>
+) -> (StatusCode, Json<Readiness>) {
+    let (database, proxy, sherlock) = tokio::join!(
+        check("database", true, app.database.verify_db()),
+        check("proxy", true, reach_proxy(&app.clients.proxy_link)),
+        check("sherlock", false, app.sherlock.probe())
+    );
+    let checks = vec!(database, proxy, sherlock);
+
+    let ready = checks.iter().all(|check| check.ok || !check.required);
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+    (status, Json(Readiness { ready, checks }))
+}
+
+async fn check ( name: &'static str, required: bool, probe: impl Future<Output = Result<()>> ) -> Check {
+    let started = Instant::now();
+    let outcome = tokio::time::timeout(CHECK_TIMEOUT, probe).await
+        .context(format!("No answer within {}s!", CHECK_TIMEOUT.as_secs()))
+        .and_then(|outcome| outcome);
+
+    Check {
+        name,
+        required,
+        ok:         outcome.is_ok(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        error:      outcome.err().map(|e| describe(&e))
+    }
+}
+async fn reach_proxy ( proxy_link: &str ) -> Result<()> {
+    let url = reqwest::Url::parse(proxy_link)
+        .context("PROXY_LINK is not a valid URL!")?;
+    let host = url.host_str()
+        .ok_or_else(|| anyhow!("PROXY_LINK has no host!"))?;
+    let port = url.port_or_known_default()
+        .ok_or_else(|| anyhow!("PROXY_LINK has no port!"))?;
+
+    tokio::net::TcpStream::connect((host, port)).await
+        .context("Can't connect to the proxy!")?;
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProviderStatus {
+    provider: Provider,
+    breaker:  BreakerState,
+    #[serde(flatten)]
+    health:   HealthStatus
+}
+#[derive(Debug, Serialize)]
+pub struct Status {
+    /// `degraded` while any provider's last call failed or its breaker
+    ///  isn't closed.
+    status:    &'static str,
+    providers: Vec<ProviderStatus>
+}
+
+/// How each provider's calls have been going, for the status page.
+pub async fn status (
+    State(app): State<AppState>
+) -> Json<Status> {
+    let providers: Vec<ProviderStatus> = Provider::ALL.into_iter()
+        .map(|provider| {
+            let resilience = app.resilience(provider);
+
+            ProviderStatus {
+                provider,
+                breaker: resilience.breaker.state(),
+                health:  resilience.health.status()
+            }
+        })
+        .collect();
+
+    let degraded = providers.iter()
+        .any(|provider| provider.health.state == HealthState::Down
+            || provider.breaker != BreakerState::Closed);
+
+    Json(Status {
+        status: if degraded { "degraded" } else { "ok" },
+        providers
+    })
+}
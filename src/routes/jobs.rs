@@ -0,0 +1,311 @@
+use crate::helper::extract::{ Path, Query };
+use crate::helper::types::{ API, AppState, AppError, PII };
+use crate::helper::auth::{ BillableUser, OperatorAuth, Receipt, UserApiKey };
+use crate::helper::cache::ForceFresh;
+use crate::helper::request_id;
+use crate::helper::findings::{ FormatParams, Formatted };
+use crate::helper::usernames::ValidationMode;
+use crate::apis::jobs::{ Job, JobError, JobStatus, LookupResult };
+use crate::apis::sherlock::{ SherlockSite, SiteStatus };
+use crate::apis::billing::Reservation;
+use crate::apis::cache::CacheKey;
+
+use std::future::Future;
+
+use axum::{
+    extract::{ State, rejection::JsonRejection },
+    http::StatusCode,
+    Json
+};
+use anyhow::{ Result, anyhow, Context };
+use chrono::Utc;
+use serde::Deserialize;
+use tokio::sync::{ mpsc, watch };
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobRequest {
+    api:      API,
+    pii_type: PII,
+    term:     String,
+    /// Only for `snusbase_query` and `snusbase_hashing`.
+    #[serde(default)]
+    wildcard: bool,
+    /// Username validation mode; only for `sherlock`.
+    #[serde(default)]
+    validation: Option<ValidationMode>
+}
+
+/// Validates a job's lookup up front, returning what it will cost.
+fn plan ( app: &AppState, request: &JobRequest ) -> Result<i32, AppError> {
+    if request.wildcard {
+        if !request.api.supports_wildcard() {
+            return Err(AppError::BadRequest(anyhow!(
+                "Wildcard searches aren't supported by the {} API!", request.api.as_str()
+            )));
+        }
+
+        app.snusbase
+            .validate_wildcard(&request.term)
+            .map_err(AppError::BadRequest)?;
+    }
+    if request.api == API::Sherlock {
+        app.sherlock.check_username(&request.term, request.validation)?;
+    }
+
+    Ok(request.api.cost(request.wildcard))
+}
+
+/// Submits a lookup to run in the background and returns its job at once.
+///
+/// The lookup's full cost is reserved now, so an unaffordable job is
+///  refused up front; nothing is charged until it completes, and a cache
+///  hit or failure settles for less (or nothing).
+pub async fn submit_job (
+    State(app): State<AppState>,
+    OperatorAuth(operator): OperatorAuth,
+    user: BillableUser,
+    fresh: ForceFresh,
+    request: Result<Json<JobRequest>, JsonRejection>
+) -> Result<(StatusCode, Receipt, Json<Job>), AppError> {
+    let Json(request) = request?;
+
+    // The job runs the same lookup its own route would, so it needs the same scope
+    let scope = request.api.scope();
+    if !operator.scopes.contains(&scope) {
+        return Err(AppError::Forbidden(anyhow!(
+            "Operator key lacks the `{}` scope needed to run {} jobs!",
+            scope.as_str(), request.api.as_str()
+        )));
+    }
+    if !request.api.supported_pii().contains(&request.pii_type) {
+        return Err(AppError::InvalidPII(anyhow!("Invalid PII type for the {} API!", request.api.as_str())));
+    }
+    if request.term.trim().is_empty() {
+        return Err(AppError::BadRequest(anyhow!("Job term must not be empty!")));
+    }
+
+    let cost = plan(&app, &request)?;
+    let reservation = user.reserve(&app, cost).await?;
+
+    let job = Job {
+        id:       Uuid::new_v4().to_string(),
+        api:      request.api,
+        pii_type: request.pii_type.clone(),
+        term:     request.term.clone(),
+        status:   JobStatus::Running,
+        found:    0,
+        reserved: cost,
+        cost:     None,
+        error:    None,
+
+        created_at:  Utc::now(),
+        finished_at: None,
+
+        owner:  user.0.api_key.clone(),
+        result: None
+    };
+    let cancelled = app.jobs.insert(job.clone());
+
+    // Run detached from this request, so the job outlives the connection
+    tokio::spawn(request_id::propagate({
+        let (app, id) = (app.clone(), job.id.clone());
+
+        async move {
+            let outcome = run_lookup(&app, &id, reservation, request, fresh, cancelled).await
+                .map(|(receipt, result)| (receipt.cost, result))
+                .map_err(|e| JobError {
+                    code:  e.code(),
+                    error: e.error().to_string()
+                });
+
+            app.jobs.finish(&id, outcome);
+        }
+    }));
+
+    // Nothing is charged yet; the job settles its own reservation
+    Ok((StatusCode::ACCEPTED, Receipt { cost: 0 }, Json(job)))
+}
+
+async fn run_lookup (
+    app: &AppState,
+    id: &str,
+    reservation: Reservation,
+    request: JobRequest,
+    fresh: ForceFresh,
+    mut cancelled: watch::Receiver<bool>
+) -> Result<(Receipt, LookupResult), AppError> {
+    // Cancelled before it started; dropping the reservation releases it
+    if *cancelled.borrow() {
+        return Err(job_cancelled());
+    }
+
+    let JobRequest { api, pii_type, term, wildcard, .. } = request;
+    let key = CacheKey::new(api.provider(), api.operation(wildcard), &pii_type, &term);
+    let usage = (api.category().to_string(), api.service(wildcard).to_string(), pii_type.clone(), term.clone());
+
+    match api {
+        API::SnusbaseQuery => {
+            let (receipt, _, res) = app.settle_lookup(reservation, key, fresh, usage, unless_cancelled(&mut cancelled, async {
+                Ok(app.snusbase
+                    .get_by(&pii_type, term.clone(), wildcard)
+                    .await?)
+            })).await?;
+
+            Ok((receipt, LookupResult::Db(res)))
+        },
+        API::SnusbaseHashing => {
+            let (receipt, _, res) = app.settle_lookup(reservation, key, fresh, usage, unless_cancelled(&mut cancelled, async {
+                Ok(match pii_type {
+                    PII::Password => app.snusbase
+                        .rehash(term.clone(), wildcard)
+                        .await,
+                    _ => app.snusbase
+                        .dehash(term.clone(), wildcard)
+                        .await
+                }.context("Failed to get Hashing results from Snusbase!")?)
+            })).await?;
+
+            Ok((receipt, LookupResult::Hashes(res)))
+        },
+        API::SnusbaseGeolocation => {
+            let (receipt, _, res) = app.settle_lookup(reservation, key, fresh, usage, unless_cancelled(&mut cancelled, async {
+                Ok(app.snusbase
+                    .whois_ip_query(vec!(term.clone())).await
+                    .context("Failed to get Geolocation results from Snusbase!")?)
+            })).await?;
+
+            Ok((receipt, LookupResult::Geo(res)))
+        },
+        API::BulkVS => {
+            let (receipt, _, res) = app.settle_lookup(reservation, key, fresh, usage, unless_cancelled(&mut cancelled, async {
+                Ok(app.bulkvs
+                    .query_phone_number(&term).await
+                    .context("Failed to get CNAM! from BulkVS!")?)
+            })).await?;
+
+            Ok((receipt, LookupResult::Cnam(res)))
+        },
+        API::Sherlock => {
+            // Count sites as Sherlock reports them, so polling shows progress
+            let (found, mut reported) = mpsc::unbounded_channel::<SherlockSite>();
+            let progress = tokio::spawn(request_id::propagate({
+                let (app, id) = (app.clone(), id.to_owned());
+
+                async move {
+                    // Sherlock may keep reporting after a cancel, which shouldn't count
+                    while let Some(entry) = reported.recv().await {
+                        if entry.status != SiteStatus::Claimed {
+                            continue;
+                        }
+
+                        app.jobs.update(&id, |job| if !job.status.is_finished() {
+                            job.found += 1;
+                        });
+                    }
+                }
+            }));
+
+            let res = app.settle_lookup(reservation, key, fresh, usage, unless_cancelled(&mut cancelled, async {
+                Ok(app.sherlock
+                    .report_potential_profiles(term.clone(), found).await
+                    .context("Failed to get Sherlock! from Sherlock!")?)
+            })).await;
+            progress.abort();
+
+            let (receipt, _, res) = res?;
+            app.jobs.update(id, |job| job.found = res.sites.len());
+
+            Ok((receipt, LookupResult::Profiles(res)))
+        }
+    }
+}
+
+// A cancelled job only stops while it's waiting on the provider. Once the
+//  lookup returns, settling runs to the end, so a cancel can't land
+//  between the charge and its usage log.
+async fn unless_cancelled<T> (
+    cancelled: &mut watch::Receiver<bool>,
+    fetch: impl Future<Output = Result<T, AppError>>
+) -> Result<T, AppError> {
+    tokio::select! {
+        biased;
+        res = fetch => res,
+        Ok(_) = cancelled.wait_for(|cancelled| *cancelled) => Err(job_cancelled())
+    }
+}
+fn job_cancelled () -> AppError {
+    AppError::Conflict(anyhow!("Job was cancelled!"))
+}
+
+pub async fn get_job (
+    State(app): State<AppState>,
+    UserApiKey(owner): UserApiKey,
+    Path(id): Path<String>
+) -> Result<Json<Job>, AppError> {
+    app.jobs
+        .get(&id, &owner)
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(anyhow!("No job `{id}`!")))
+}
+
+/// The finished job's raw lookup response, or its normalized findings.
+pub async fn job_result (
+    State(app): State<AppState>,
+    UserApiKey(owner): UserApiKey,
+    Path(id): Path<String>,
+    Query(FormatParams { format }): Query<FormatParams>
+) -> Result<Json<Formatted<LookupResult>>, AppError> {
+    let job = app.jobs
+        .get(&id, &owner)
+        .ok_or_else(|| AppError::NotFound(anyhow!("No job `{id}`!")))?;
+
+    match (job.status, job.result) {
+        (JobStatus::Succeeded, Some(result)) => Ok(Json(format.apply(result, &app.fields))),
+        (JobStatus::Running, _) => Err(AppError::Conflict(anyhow!("Job `{id}` is still running!"))),
+        (status, _) => Err(AppError::Conflict(anyhow!("Job `{id}` has no result; it {}!", match status {
+            JobStatus::Cancelled => "was cancelled",
+            _ => "failed"
+        })))
+    }
+}
+
+/// Cancels a running job, releasing its reservation. Cancelling a job
+///  that already finished leaves it as it was.
+pub async fn cancel_job (
+    State(app): State<AppState>,
+    UserApiKey(owner): UserApiKey,
+    Path(id): Path<String>
+) -> Result<Json<Job>, AppError> {
+    app.jobs
+        .cancel(&id, &owner)
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(anyhow!("No job `{id}`!")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn cancelling_stops_the_fetch_but_not_a_finished_one () {
+        let (cancel, mut cancelled) = watch::channel(false);
+
+        let fetch = unless_cancelled(&mut cancelled, async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        });
+        let canceller = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            cancel.send_replace(true);
+        };
+        let (res, _) = tokio::join!(fetch, canceller);
+        assert!(matches!(res, Err(AppError::Conflict(_))));
+
+        // Already flagged, but a fetch that's done still settles
+        let res = unless_cancelled(&mut cancelled, async { Ok(7) }).await;
+        assert!(matches!(res, Ok(7)));
+    }
+}
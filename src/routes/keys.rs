@@ -0,0 +1,56 @@
+use crate::helper::types::{ AppState, AppError, Scope };
+use crate::helper::auth::OperatorAuth;
+use crate::apis::database::OperatorKey;
+use crate::apis::operators::MintedKey;
+
+use axum::{
+    extract::{ State, rejection::JsonRejection },
+    Json
+};
+use anyhow::{ anyhow, Result };
+use chrono::{ DateTime, Utc };
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateKeyRequest {
+    name:       String,
+    scopes:     Vec<Scope>,
+    expires_at: Option<DateTime<Utc>>
+}
+
+pub async fn list_keys (
+    State(app): State<AppState>
+) -> Result<Json<Vec<OperatorKey>>, AppError> {
+    Ok(Json(app.operators
+        .list().await?))
+}
+pub async fn create_key (
+    State(app): State<AppState>,
+    OperatorAuth(operator_key): OperatorAuth,
+    request: Result<Json<CreateKeyRequest>, JsonRejection>
+) -> Result<Json<MintedKey>, AppError> {
+    let Json(request) = request?;
+
+    if request.name.trim().is_empty() {
+        return Err(AppError::BadRequest(anyhow!("Key name must not be empty!")));
+    }
+    if request.scopes.is_empty() {
+        return Err(AppError::BadRequest(anyhow!("Key must be granted at least one scope!")));
+    }
+    if let Some(scope) = request.scopes.iter().find(|scope| !operator_key.scopes.contains(scope)) {
+        return Err(AppError::Forbidden(anyhow!("Cannot grant the `{}` scope without holding it!", scope.as_str())));
+    }
+    if request.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(AppError::BadRequest(anyhow!("Key expiry must be in the future!")));
+    }
+
+    Ok(Json(app.operators
+        .mint(request.name, request.scopes, request.expires_at).await?))
+}
+pub async fn revoke_key (
+    State(app): State<AppState>,
+    key_id: String
+) -> Result<Json<OperatorKey>, AppError> {
+    Ok(Json(app.operators
+        .revoke(key_id.trim().to_owned()).await?))
+}
@@ -0,0 +1,53 @@
+use crate::helper::types::{ AppState, AppError, Provider };
+use crate::helper::metrics::{ Exposition, metrics as registry };
+use crate::apis::health::HealthState;
+
+use axum::{
+    extract::State,
+    http::{ HeaderMap, header },
+    response::IntoResponse
+};
+use anyhow::anyhow;
+use subtle::ConstantTimeEq;
+
+/// Every metric in the Prometheus text format. If `METRICS_TOKEN` is set,
+///  scrapers must send it as a bearer token.
+pub async fn metrics (
+    State(app): State<AppState>,
+    headers: HeaderMap
+) -> Result<impl IntoResponse, AppError> {
+    if let Ok(token) = std::env::var("METRICS_TOKEN") {
+        let presented = headers.get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        if !bool::from(presented.as_bytes().ct_eq(token.as_bytes())) {
+            return Err(AppError::Unauthorized(anyhow!("Metrics require the `METRICS_TOKEN` bearer token!")));
+        }
+    }
+
+    let mut out = Exposition::default();
+    registry().write(&mut out);
+
+    out.family("osint_jobs_active", "gauge", "Background jobs still running.");
+    out.sample("osint_jobs_active", &[], app.jobs.running());
+
+    out.family("osint_provider_up", "gauge", "Whether the provider's last call succeeded (unknown counts as up).");
+    for provider in Provider::ALL {
+        let up = app.resilience(provider).health.status().state != HealthState::Down;
+
+        out.sample("osint_provider_up", &[ ("provider", provider.as_str()) ], u8::from(up));
+    }
+
+    out.family("osint_circuit_breaker_state", "gauge", "Each provider's circuit breaker: 0 closed, 1 half-open, 2 open.");
+    for provider in Provider::ALL {
+        out.sample(
+            "osint_circuit_breaker_state",
+            &[ ("provider", provider.as_str()) ],
+            app.resilience(provider).breaker.state().level()
+        );
+    }
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out.finish()))
+}
@@ -1,5 +1,11 @@
 pub mod tally;
 pub mod nocodb;
+pub mod keys;
+pub mod capabilities;
+pub mod pivot;
+pub mod jobs;
+pub mod health;
+pub mod metrics;
 
 pub mod tele;
 pub mod db;
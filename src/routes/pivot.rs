@@ -0,0 +1,485 @@
+use crate::helper::types::{ API, AppState, AppError, PII };
+use crate::helper::auth::{ BillableUser, Receipt };
+use crate::helper::cache::ForceFresh;
+use crate::helper::request_id;
+use crate::helper::fields::FieldCategory;
+use crate::helper::findings::Normalize;
+use crate::helper::usernames::ValidationMode;
+use crate::apis::cache::CacheKey;
+
+use std::collections::{ HashMap, HashSet };
+
+use axum::{
+    extract::{ State, rejection::JsonRejection },
+    Json
+};
+use anyhow::{ Result, anyhow };
+use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+use tokio::task::JoinSet;
+
+/// What a node in the pivot graph is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityKind {
+    Email,
+    Username,
+    Phone,
+    Ip,
+    Hash,
+    Password,
+    Name,
+    Company,
+    /// A profile URL Sherlock found for a username.
+    Profile
+}
+impl EntityKind {
+    pub fn as_str ( &self ) -> &'static str {
+        match self {
+            EntityKind::Email    => "email",
+            EntityKind::Username => "username",
+            EntityKind::Phone    => "phone",
+            EntityKind::Ip       => "ip",
+            EntityKind::Hash     => "hash",
+            EntityKind::Password => "password",
+            EntityKind::Name     => "name",
+            EntityKind::Company  => "company",
+            EntityKind::Profile  => "profile"
+        }
+    }
+    /// The PII type this entity is looked up as, if it can be.
+    pub fn pii ( &self ) -> Option<PII> {
+        match self {
+            EntityKind::Email    => Some(PII::Email),
+            EntityKind::Username => Some(PII::Username),
+            EntityKind::Phone    => Some(PII::Phone),
+            EntityKind::Ip       => Some(PII::Ip),
+            EntityKind::Hash     => Some(PII::Hash),
+            EntityKind::Password => Some(PII::Password),
+            EntityKind::Name     => Some(PII::Name),
+            EntityKind::Company  => Some(PII::Company),
+            EntityKind::Profile  => None
+        }
+    }
+    /// The APIs an entity of this kind is expanded through. Passwords,
+    ///  names and the like are kept as findings but not searched on, since
+    ///  they match far too many unrelated records.
+    pub fn expands_through ( &self ) -> &'static [API] {
+        match self {
+            EntityKind::Email    => &[ API::SnusbaseQuery ],
+            EntityKind::Username => &[ API::SnusbaseQuery, API::Sherlock ],
+            EntityKind::Phone    => &[ API::SnusbaseQuery, API::BulkVS ],
+            EntityKind::Ip       => &[ API::SnusbaseQuery, API::SnusbaseGeolocation ],
+            EntityKind::Hash     => &[ API::SnusbaseHashing ],
+            _ => &[]
+        }
+    }
+    pub fn from_pii ( pii_type: &PII ) -> Option<Self> {
+        match pii_type {
+            PII::Email    => Some(EntityKind::Email),
+            PII::Username => Some(EntityKind::Username),
+            PII::Phone    => Some(EntityKind::Phone),
+            PII::Ip       => Some(EntityKind::Ip),
+            PII::Hash     => Some(EntityKind::Hash),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PivotRequest {
+    pii_type:  PII,
+    term:      String,
+    /// How many hops out from the seed to expand.
+    #[serde(default = "default_depth")]
+    max_depth: usize,
+    /// The most this pivot may charge, across every lookup it runs.
+    max_spend: i32,
+    /// Restricts expansion to these APIs; all of them if omitted.
+    #[serde(default)]
+    apis:      Option<Vec<API>>,
+    /// Username validation mode for every username searched on Sherlock,
+    ///  the seed's and those found along the way.
+    #[serde(default)]
+    validation: Option<ValidationMode>
+}
+fn default_depth () -> usize { 1 }
+
+#[derive(Debug, Serialize)]
+pub struct Entity {
+    id:    String,
+    kind:  EntityKind,
+    value: String,
+    depth: usize
+}
+#[derive(Debug, Serialize)]
+pub struct Edge {
+    from: String,
+    to:   String,
+    api:  API
+}
+#[derive(Debug, Serialize)]
+pub struct StepError {
+    entity: String,
+    api:    API,
+    code:   &'static str,
+    error:  String
+}
+#[derive(Debug, Serialize)]
+pub struct PivotGraph {
+    entities:  Vec<Entity>,
+    edges:     Vec<Edge>,
+    errors:    Vec<StepError>,
+    spent:     i32,
+    max_spend: i32,
+    /// Whether expansion stopped early, because of the spend limit, the
+    ///  user's balance, or the entity limit.
+    truncated: bool
+}
+
+/// The id of an entity, normalized the way cache keys are so the same
+///  email or hash found twice in different case is one node.
+fn entity_id ( kind: EntityKind, value: &str ) -> String {
+    let value = value.trim();
+    let value = match kind {
+        EntityKind::Email | EntityKind::Hash => value.to_lowercase(),
+        _ => value.to_owned()
+    };
+
+    format!("{}:{value}", kind.as_str())
+}
+
+/// Starts from a seed term and expands it breadth-first through the
+///  provider APIs, feeding what each lookup finds into the next, until
+///  `max_depth` hops, `max_spend` credits, or the entity limit is reached.
+///
+/// Every lookup goes through the response cache and is billed and logged
+///  under `Pivot` individually, so a pivot costs what the same lookups
+///  would have on their own routes.
+pub async fn pivot (
+    State(app): State<AppState>,
+    user: BillableUser,
+    fresh: ForceFresh,
+    request: Result<Json<PivotRequest>, JsonRejection>
+) -> Result<(Receipt, Json<PivotGraph>), AppError> {
+    let Json(request) = request?;
+
+    let Some(seed_kind) = EntityKind::from_pii(&request.pii_type) else {
+        return Err(AppError::InvalidPII(anyhow!("Pivots can't start from a {}!", request.pii_type.as_str())));
+    };
+    if request.term.trim().is_empty() {
+        return Err(AppError::BadRequest(anyhow!("Pivot term must not be empty!")));
+    }
+    if request.max_depth == 0 || request.max_depth > app.pricing.pivot_max_depth {
+        return Err(AppError::BadRequest(anyhow!(
+            "Pivot depth must be between 1 and {}!",
+            app.pricing.pivot_max_depth
+        )));
+    }
+    if request.max_spend <= 0 {
+        return Err(AppError::BadRequest(anyhow!("Pivot max_spend must be positive!")));
+    }
+    if request.apis.as_ref().is_some_and(Vec::is_empty) {
+        return Err(AppError::BadRequest(anyhow!("Pivot must be allowed at least one API!")));
+    }
+
+    let graph = expand(&app, &user, fresh, request, seed_kind).await?;
+
+    // Each lookup settled its own charge; this totals them for the response
+    Ok((Receipt { cost: graph.spent }, Json(graph)))
+}
+
+async fn expand (
+    app: &AppState,
+    user: &BillableUser,
+    fresh: ForceFresh,
+    request: PivotRequest,
+    seed_kind: EntityKind
+) -> Result<PivotGraph, AppError> {
+    let mut graph = PivotGraph {
+        entities:  Vec::new(),
+        edges:     Vec::new(),
+        errors:    Vec::new(),
+        spent:     0,
+        max_spend: request.max_spend,
+        truncated: false
+    };
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut seen_edges: HashSet<(String, String, API)> = HashSet::new();
+
+    let seed_id = entity_id(seed_kind, &request.term);
+    seen.insert(seed_id.clone(), 0);
+    graph.entities.push(Entity {
+        id:    seed_id.clone(),
+        kind:  seed_kind,
+        value: request.term.trim().to_owned(),
+        depth: 0
+    });
+
+    let mut frontier = vec!(seed_id);
+    let mut out_of_balance = None;
+    for depth in 0..request.max_depth {
+        // Admit as many of this hop's lookups as the spend limit allows,
+        //  assuming each is charged in full
+        let mut budget = graph.spent;
+        let mut queries = JoinSet::new();
+        for id in &frontier {
+            let entity = &graph.entities[seen[id]];
+            let Some(pii_type) = entity.kind.pii() else {
+                continue;
+            };
+
+            for &api in entity.kind.expands_through() {
+                if request.apis.as_ref().is_some_and(|apis| !apis.contains(&api)) {
+                    continue;
+                }
+                // Refused usernames are noted, and don't count against the budget
+                if api == API::Sherlock {
+                    if let Err(rejection) = app.sherlock.check_username(&entity.value, request.validation) {
+                        let e = AppError::from(rejection);
+                        graph.errors.push(StepError {
+                            entity: id.clone(),
+                            api,
+                            code:   e.code(),
+                            error:  e.error().to_string()
+                        });
+
+                        continue;
+                    }
+                }
+
+                let cost = api.cost(false);
+                if budget + cost > request.max_spend {
+                    graph.truncated = true;
+
+                    continue;
+                }
+                budget += cost;
+
+                let (app, user) = (app.clone(), user.clone());
+                let (id, pii_type, term) = (id.clone(), pii_type.clone(), entity.value.clone());
+                queries.spawn(request_id::propagate(async move {
+                    let res = lookup(&app, &user, fresh, api, pii_type, term).await;
+
+                    (id, api, res)
+                }));
+            }
+        }
+
+        let mut next = Vec::new();
+        while let Some(joined) = queries.join_next().await {
+            let (from, api, res) = joined
+                .map_err(|e| AppError::Internal(anyhow!("Pivot lookup task failed: {e}")))?;
+
+            let found = match res {
+                Ok((receipt, found)) => {
+                    graph.spent += receipt.cost;
+
+                    found
+                },
+                Err(e) => {
+                    graph.errors.push(StepError {
+                        entity: from,
+                        api,
+                        code:   e.code(),
+                        error:  e.error().to_string()
+                    });
+                    if let AppError::PaymentRequired(_) = e {
+                        out_of_balance = Some(e);
+                    }
+
+                    continue;
+                }
+            };
+
+            for (kind, value) in found {
+                if value.trim().is_empty() {
+                    continue;
+                }
+
+                let to = entity_id(kind, &value);
+                if to == from {
+                    continue;
+                }
+                if !seen.contains_key(&to) {
+                    if graph.entities.len() >= app.pricing.pivot_max_entities {
+                        graph.truncated = true;
+
+                        continue;
+                    }
+
+                    seen.insert(to.clone(), graph.entities.len());
+                    graph.entities.push(Entity {
+                        id:    to.clone(),
+                        kind,
+                        value: value.trim().to_owned(),
+                        depth: depth + 1
+                    });
+                    next.push(to.clone());
+                }
+
+                if seen_edges.insert((from.clone(), to.clone(), api)) {
+                    graph.edges.push(Edge { from: from.clone(), to, api });
+                }
+            }
+        }
+
+        // Nothing further can be paid for
+        if out_of_balance.is_some() {
+            graph.truncated = true;
+
+            break;
+        }
+
+        frontier = next;
+    }
+
+    // A pivot the user couldn't afford any of is a payment error, not an
+    //  empty graph
+    if let (Some(e), 0) = (out_of_balance, graph.spent) {
+        return Err(e);
+    }
+
+    Ok(graph)
+}
+
+/// Runs one lookup, billed like its standalone route, and returns what it
+///  found that could be pivoted on (or kept as a finding).
+async fn lookup (
+    app: &AppState,
+    user: &BillableUser,
+    fresh: ForceFresh,
+    api: API,
+    pii_type: PII,
+    term: String
+) -> Result<(Receipt, Vec<(EntityKind, String)>), AppError> {
+    let cost = api.cost(false);
+    let usage = ("Pivot".to_string(), api.service(false).to_string(), pii_type.clone(), term.clone());
+    let schema = &app.fields;
+
+    match api {
+        API::SnusbaseQuery => {
+            let (receipt, _, res) = app.billed_lookup(
+                user,
+                CacheKey::new(api.provider(), api.operation(false), &pii_type, &term),
+                fresh,
+                cost,
+                usage,
+                async {
+                    Ok(app.snusbase
+                        .get_by(&pii_type, term.clone(), false)
+                        .await?)
+                }
+            ).await?;
+
+            let mut found = Vec::new();
+            found.extend(res._emails(schema).into_iter().map(|value| (EntityKind::Email, value)));
+            found.extend(res._usernames(schema).into_iter().map(|value| (EntityKind::Username, value)));
+            found.extend(res._values(schema, FieldCategory::Phone).into_iter().map(|value| (EntityKind::Phone, value)));
+            found.extend(res._last_ips(schema).into_iter().map(|value| (EntityKind::Ip, value)));
+            found.extend(res._values(schema, FieldCategory::Hash).into_iter().map(|value| (EntityKind::Hash, value)));
+            found.extend(res._passwords(schema).into_iter().map(|value| (EntityKind::Password, value)));
+            found.extend(res._names(schema).into_iter().map(|value| (EntityKind::Name, value)));
+            found.extend(res._companies(schema).into_iter().map(|value| (EntityKind::Company, value)));
+
+            Ok((receipt, found))
+        },
+        API::SnusbaseHashing => {
+            let (receipt, _, res) = app.billed_lookup(
+                user,
+                CacheKey::new(api.provider(), api.operation(false), &pii_type, &term),
+                fresh,
+                cost,
+                usage,
+                async {
+                    Ok(match pii_type {
+                        PII::Password => app.snusbase
+                            .rehash(term.clone(), false)
+                            .await?,
+                        _ => app.snusbase
+                            .dehash(term.clone(), false)
+                            .await?
+                    })
+                }
+            ).await?;
+
+            let found = res.results.values()
+                .flatten()
+                .filter_map(Value::as_object)
+                .flatten()
+                .filter_map(|(field, value)| {
+                    let kind = match schema.classify(field) {
+                        FieldCategory::Password => EntityKind::Password,
+                        FieldCategory::Hash     => EntityKind::Hash,
+                        _ => return None
+                    };
+
+                    value.as_str().map(|value| (kind, value.to_owned()))
+                })
+                .collect();
+
+            Ok((receipt, found))
+        },
+        API::SnusbaseGeolocation => {
+            let (receipt, _, res) = app.billed_lookup(
+                user,
+                CacheKey::new(api.provider(), api.operation(false), &PII::Ip, &term),
+                fresh,
+                cost,
+                usage,
+                async {
+                    Ok(app.snusbase
+                        .whois_ip_query(vec!(term.clone()))
+                        .await?)
+                }
+            ).await?;
+
+            let found = res.results.values()
+                .flatten()
+                .filter(|(field, _)| schema.classify(field) == FieldCategory::Company)
+                .filter_map(|(_, value)| value.as_str())
+                .map(|value| (EntityKind::Company, value.to_owned()))
+                .collect();
+
+            Ok((receipt, found))
+        },
+        API::BulkVS => {
+            let (receipt, _, res) = app.billed_lookup(
+                user,
+                CacheKey::new(api.provider(), api.operation(false), &PII::Phone, &term),
+                fresh,
+                cost,
+                usage,
+                async {
+                    Ok(app.bulkvs
+                        .query_phone_number(&term)
+                        .await?)
+                }
+            ).await?;
+
+            Ok((receipt, res.name.into_iter().map(|name| (EntityKind::Name, name)).collect()))
+        },
+        API::Sherlock => {
+            let (receipt, _, res) = app.billed_lookup(
+                user,
+                CacheKey::new(api.provider(), api.operation(false), &PII::Username, &term),
+                fresh,
+                cost,
+                usage,
+                async {
+                    Ok(app.sherlock
+                        .get_and_stringify_potential_profiles(term.clone())
+                        .await?)
+                }
+            ).await?;
+
+            let found = res.normalize(schema)
+                .into_iter()
+                .filter_map(|finding| finding.url)
+                .map(|url| (EntityKind::Profile, url))
+                .collect();
+
+            Ok((receipt, found))
+        }
+    }
+}
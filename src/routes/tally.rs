@@ -1,359 +1,186 @@
+use crate::helper::extract::{ Path, Query };
 use crate::helper::types::{ API, AppState, PII, AppError };
+use crate::helper::auth::{ BillableUser, Receipt };
+use crate::helper::cache::{ CacheInfo, ForceFresh };
+use crate::helper::fields::{ FieldCategory, Tally };
+use crate::helper::usernames::ValidationMode;
+use crate::apis::cache::CacheKey;
 
-use std::collections::HashSet;
 use axum::{
-    http::header::HeaderMap,
-    extract::{ State, Path },
+    extract::State,
     Json
 };
 use anyhow::{ Result, anyhow, Context };
-use serde::{ Serialize, Deserialize };
+use serde::Deserialize;
 use serde_json::Value;
 
-#[derive(Debug, Serialize, Deserialize, Default)]
-pub struct Tally {
-    usernames: usize,
-    emails:    usize,
-    phones:    usize,
-    hashes:    usize,
-    salts:     usize,
-    ips:       usize,
-    names:     usize,
-    passwords: usize,
-    addresses: usize,
-    companies: usize,
-    other:     usize
+#[derive(Debug, Default, Deserialize)]
+pub struct TallyParams {
+    /// Also count per dump, for the providers that return dumps.
+    #[serde(default)]
+    breakdown: bool,
+    /// Username validation mode, for Sherlock.
+    #[serde(default)]
+    validation: Option<ValidationMode>
 }
 
+/// Runs the provider lookup for `api` (or reuses a cached one), billed
+///  at the provider's preview price, and counts what it found.
+///
+/// The raw results are retained in the response cache so that a paid
+///  route asked for the same term shortly after doesn't go upstream again.
 pub async fn tally_api ( 
     State(app): State<AppState>,
     Path((api, pii_type)): Path<(API, PII)>,
-    headers: HeaderMap,
+    Query(params): Query<TallyParams>,
+    user: BillableUser,
+    fresh: ForceFresh,
     pii: String
-) -> Result<Json<Tally>, AppError> {
-    // Verify the API key
-    app.verify_api_key_header(&headers)?;
-
-    match api {
-        API::SnusbaseQuery => {
-            let mut tally = Tally::default();
-
-            let mut found_usernames = HashSet::new();
-            let mut found_emails    = HashSet::new();
-            let mut found_phones    = HashSet::new();
-            let mut found_names     = HashSet::new();
-            let mut found_addresses = HashSet::new();
-            let mut found_companies = HashSet::new();
-            let mut found_ips       = HashSet::new();
-            let mut found_passwords = HashSet::new();
-            let mut found_hashes    = HashSet::new();
-            let mut found_salts     = HashSet::new();
-            let mut found_other     = HashSet::new();
-
-            let res;
-
-            let pii_value = Value::String(pii.clone());
-
-            // Query Snusbase
-            match pii_type {
-                PII::Email => {
-                    res = app.snusbase.lock()
-                        .await
-                        .get_by_email(pii)
-                        .await?;
-
-                    found_emails.insert(&pii_value);
-                },
-                PII::Username => {
-                    res = app.snusbase.lock()
-                        .await
-                        .get_by_username(pii)
-                        .await?;
-
-                    found_usernames.insert(&pii_value);
-                },
-                PII::Hash => {
-                    res = app.snusbase.lock()
-                        .await
-                        .get_by_hash(pii)
-                        .await?;
-
-                    found_hashes.insert(&pii_value);
-                },
-                PII::Ip => {
-                    res = app.snusbase.lock()
-                        .await
-                        .get_by_last_ip(pii)
-                        .await?;
-
-                    found_ips.insert(&pii_value);
-                },
-                PII::Name => {
-                    res = app.snusbase.lock()
-                        .await
-                        .get_by_name(pii)
-                        .await?;
-
-                    found_names.insert(&pii_value);
-                },
-                PII::Password => {
-                    res = app.snusbase.lock()
-                        .await
-                        .get_by_password(pii)
-                        .await?;
-
-                    found_passwords.insert(&pii_value);
-                },
-                _ => {
-                    return Err(anyhow!("Invalid PII type for Snusbase Query API!"))?;
-                }
-            }
-            
-            println!("Res: {res:#?}");
-
-            for (_dump_name, dump_content) in &res.results {
-                for entry in dump_content {
-                    // If the result is already found, skip it,
-                    //  otherwise add it to the tally
-                    if let Some(username) = entry.get("username") {
-                        if !found_usernames.contains(username) {
-                            tally.usernames += 1;
-                            found_usernames.insert(username);
-                        }
-                    }
-
-                    if let Some(email) = entry.get("email") {
-                        if !found_emails.contains(email) {
-                            tally.emails += 1;
-                            found_emails.insert(email);
-                        }
-                    }
-
-                    if let Some(phone) = entry.get("phone") {
-                        if !found_phones.contains(phone) {
-                            tally.phones += 1;
-                            found_phones.insert(phone);
-                        }
-                    }
-
-                    if let Some(name) = entry.get("name") {
-                        if !found_names.contains(name) {
-                            tally.names += 1;
-                            found_names.insert(name);
-                        }
-                    }
-
-                    if let Some(address) = entry.get("address") {
-                        if !found_addresses.contains(address) {
-                            tally.addresses += 1;
-                            found_addresses.insert(address);
-                        }
-                    }
-
-                    if let Some(company) = entry.get("company") {
-                        if !found_companies.contains(company) {
-                            tally.companies += 1;
-                            found_companies.insert(company);
-                        }
-                    }
-
-                    // IPs
-                    if let Some(last_ip) = entry.get("last_ip") {
-                        if !found_ips.contains(last_ip) {
-                            tally.ips += 1;
-                            found_ips.insert(last_ip);
-                        }
-                    }
-                    if let Some(last_ip) = entry.get("lastip") {
-                        if !found_ips.contains(last_ip) {
-                            tally.ips += 1;
-                            found_ips.insert(last_ip);
-                        }
-                    }
-                    if let Some(last_ip) = entry.get("ip") {
-                        if !found_ips.contains(last_ip) {
-                            tally.ips += 1;
-                            found_ips.insert(last_ip);
-                        }
-                    }
+) -> Result<(Receipt, CacheInfo, Json<Tally>), AppError> {
+    if !api.supported_pii().contains(&pii_type) {
+        return Err(AppError::InvalidPII(anyhow!("Invalid PII type for the {} API!", api.as_str())));
+    }
 
-                    // Passwords, Hashes, and Salts
-                    if let Some(password) = entry.get("password") {
-                        if !found_passwords.contains(password) {
-                            tally.passwords += 1;
-                            found_passwords.insert(password);
-                        }
-                    }
-                    if let Some(hash) = entry.get("hash") {
-                        if !found_hashes.contains(hash) {
-                            tally.hashes += 1;
-                            found_hashes.insert(hash);
-                        }
-                    }
-                    if let Some(salt) = entry.get("salt") {
-                        if !found_salts.contains(salt) {
-                            tally.salts += 1;
-                            found_salts.insert(salt);
-                        }
-                    }
+    // The searched term itself isn't a new finding
+    let known: Vec<(FieldCategory, Value)> = FieldCategory::from_pii(&pii_type)
+        .map(|category| (category, Value::String(pii.clone())))
+        .into_iter()
+        .collect();
 
-                    for (key, value) in entry {
-                        if key == "username" || key == "email" || key == "phone" 
-                            || key == "name" || key == "last_ip" || key == "address" || key == "zip" 
-                            || key == "company" || key == "lastip" || key == "ip" || key == "password"
-                            || key == "hash" || key == "salt" {
-                            continue;
-                        }
+    let cost = app.pricing.preview_price(api.provider());
+    let usage = ("Tally".to_string(), api.service(false).to_string(), pii_type.clone(), pii.clone());
 
-                        if !found_other.contains(value) {
-                            tally.other += 1;
-                            found_other.insert(value);
-                        }
-                    }
+    match api {
+        API::SnusbaseQuery => {
+            // Query Snusbase (or reuse a retained result)
+            let (receipt, cache, res) = app.billed_lookup(
+                &user,
+                CacheKey::new(api.provider(), api.operation(false), &pii_type, &pii).for_preview(),
+                fresh,
+                cost,
+                usage,
+                async {
+                    Ok(app.snusbase
+                        .get_by(&pii_type, pii.clone(), false)
+                        .await?)
                 }
-            }
+            ).await?;
+
+            let tally = app.fields.tally(
+                res.results.iter()
+                    .flat_map(|(dump, entries)| entries.iter()
+                        .map(move |entry| (dump.as_str(), entry.iter()))),
+                &known,
+                params.breakdown
+            );
         
-            return Ok(Json(tally))
+            Ok((receipt, cache, Json(tally)))
         },
         API::SnusbaseHashing => {
-            let mut tally = Tally::default();
-
-            match pii_type {
-                PII::Password => {
-                    // Query Snusbase
-                    let res = app.snusbase.lock()
-                        .await
-                        .rehash(pii)
-                        .await?;
-                    
-                    println!("Res: {res:#?}");
-
-                    let mut found_hashes = HashSet::new();
-                    let mut found_salts = HashSet::new();
-
-                    for (_dump_name, dump_content) in &res.results {
-                        for entry in dump_content {
-                            // If the result is already found, skip it,
-                            //  otherwise add it to the tally
-                            if let Some(hash) = entry.get("hash") {
-                                if !found_hashes.contains(hash) {
-                                    tally.hashes += 1;
-                                    found_hashes.insert(hash);
-                                }
-                            }
-
-                            if let Some(salt) = entry.get("salt") {
-                                if !found_salts.contains(salt) {
-                                    tally.salts += 1;
-                                    found_salts.insert(salt);
-                                }
-                            }
-                        }
-                    }
-
-                    return Ok(Json(tally))
-                }
-                PII::Hash => {
-                    // Query Snusbase
-                    let res = app.snusbase.lock()
-                        .await
-                        .dehash(pii)
-                        .await?;
-                    
-                    println!("Res: {res:#?}");
-
-                    let mut found_passwords = HashSet::new();
-
-                    for (_dump_name, dump_content) in &res.results {
-                        for entry in dump_content {
-                            // If the result is already found, skip it,
-                            //  otherwise add it to the tally
-                            if let Some(password) = entry.get("password") {
-                                if !found_passwords.contains(password) {
-                                    tally.passwords += 1;
-                                    found_passwords.insert(password);
-                                }
-                            }
-                        }
-                    }
-
-                    return Ok(Json(tally))
-                },
-                _ => {
-                    Err(anyhow!("Invalid PII type for Snusbase Hashing API!"))?
+            // Query Snusbase (or reuse a retained result)
+            let (receipt, cache, res) = app.billed_lookup(
+                &user,
+                CacheKey::new(api.provider(), api.operation(false), &pii_type, &pii).for_preview(),
+                fresh,
+                cost,
+                usage,
+                async {
+                    Ok(match pii_type {
+                        PII::Password => app.snusbase
+                            .rehash(pii.clone(), false)
+                            .await?,
+                        _ => app.snusbase
+                            .dehash(pii.clone(), false)
+                            .await?
+                    })
                 }
-            }
+            ).await?;
+
+            let tally = app.fields.tally(
+                res.results.iter()
+                    .flat_map(|(dump, entries)| entries.iter()
+                        .filter_map(Value::as_object)
+                        .map(move |entry| (dump.as_str(), entry.iter()))),
+                &known,
+                params.breakdown
+            );
+
+            Ok((receipt, cache, Json(tally)))
         },
         API::SnusbaseGeolocation => {
-            match pii_type {
-                PII::Ip => {
-                    let mut tally = Tally::default();
-
-                    // Query Snusbase
-                    let res = app.snusbase.lock()
-                        .await
-                        .whois_ip_query(vec![pii])
-                        .await?;
-
-                    for (ip, content) in &res.results {
-                        println!("IP: {ip}");
-                        println!("Content: {content:#?}");
+            let mut tally = Tally::default();
 
-                        if content.get("company").is_some() || content.get("org").is_some() {
-                            tally.companies += 1;
-                        }
+            // Query Snusbase (or reuse a retained result)
+            let (receipt, cache, res) = app.billed_lookup(
+                &user,
+                CacheKey::new(api.provider(), api.operation(false), &PII::Ip, &pii).for_preview(),
+                fresh,
+                cost,
+                usage,
+                async {
+                    Ok(app.snusbase
+                        .whois_ip_query(vec![pii.clone()])
+                        .await?)
+                }
+            ).await?;
 
-                        if content.get("lat").is_some() && content.get("lon").is_some() {
-                            tally.addresses += 1;
-                        }
-                    }
+            for content in res.results.values() {
+                if content.keys().any(|key| app.fields.classify(key) == FieldCategory::Company) {
+                    tally.counts.companies += 1;
+                }
 
-                    return Ok(Json(tally))
-                },
-                _ => {
-                    Err(anyhow!("Invalid PII type for Snusbase Geolocation API!"))?
+                if content.get("lat").is_some() && content.get("lon").is_some() {
+                    tally.counts.addresses += 1;
                 }
             }
+
+            Ok((receipt, cache, Json(tally)))
         },
         API::BulkVS => {
-            match pii_type {
-                PII::Phone => {
-                    let mut tally = Tally::default();
-
-                    let bulkvs = app.bulkvs.lock().await;
-
-                    if bulkvs.query_phone_number(&pii).context("Failed to query BulkVS!")?.name.is_some() {
-                        tally.names += 1;
-                    }
+            let mut tally = Tally::default();
 
-                    return Ok(Json(tally))
-                },
-                _ => {
-                    Err(anyhow!("Invalid PII type for BulkVS API!"))?
+            // Query BulkVS (or reuse a retained result)
+            let (receipt, cache, res) = app.billed_lookup(
+                &user,
+                CacheKey::new(api.provider(), api.operation(false), &PII::Phone, &pii).for_preview(),
+                fresh,
+                cost,
+                usage,
+                async {
+                    Ok(app.bulkvs
+                        .query_phone_number(&pii).await
+                        .context("Failed to query BulkVS!")?)
                 }
+            ).await?;
+
+            if res.name.is_some() {
+                tally.counts.names += 1;
             }
+
+            Ok((receipt, cache, Json(tally)))
         },
         API::Sherlock => {
-            match pii_type {
-                PII::Username => {
-                    let mut tally = Tally::default();
+            let mut tally = Tally::default();
 
-                    let sherlock = app.sherlock.lock().await;
+            app.sherlock.check_username(&pii, params.validation)?;
+
+            // Query Sherlock (or reuse a retained result)
+            let (receipt, cache, res) = app.billed_lookup(
+                &user,
+                CacheKey::new(api.provider(), api.operation(false), &PII::Username, &pii).for_preview(),
+                fresh,
+                cost,
+                usage,
+                async {
+                    Ok(app.sherlock
+                        .get_and_stringify_potential_profiles(pii.clone())
+                        .await?)
+                }
+            ).await?;
 
-                    tally.usernames += sherlock
-                        .get_and_stringify_potential_profiles(
-                            pii,
-                            false
-                        ).await?
-                        .sites
-                        .len();
+            tally.counts.usernames += res.sites.len();
 
-                    return Ok(Json(tally))
-                },
-                _ => {
-                    Err(anyhow!("Invalid PII type for Sherlock API!"))?
-                }
-            }
+            Ok((receipt, cache, Json(tally)))
         }
     }
-}
\ No newline at end of file
+}
@@ -1,7 +1,11 @@
-use crate::helper::types::{ AppState, AppError, PII };
+use crate::helper::extract::Query;
+use crate::helper::types::{ AppState, AppError, PII, API };
+use crate::helper::auth::{ BillableUser, Receipt };
+use crate::helper::cache::{ CacheInfo, ForceFresh };
+use crate::helper::findings::{ FormatParams, Formatted };
+use crate::apis::cache::CacheKey;
 
 use axum::{
-    http::header::HeaderMap,
     extract::State,
     Json
 };
@@ -11,33 +15,26 @@ use crate::apis::bulkvs::BulkVSPhoneNumberResponse;
 
 pub async fn bulkvs_cnam ( 
     State(app): State<AppState>,
-    headers: HeaderMap,
+    Query(FormatParams { format }): Query<FormatParams>,
+    user: BillableUser,
+    fresh: ForceFresh,
     pii: String
-) -> Result<Json<BulkVSPhoneNumberResponse>, AppError> {
-    // Verify the API key
-    app.verify_api_key_header(&headers)?;
-
-    let cost = crate::COST_PER_TELE_BULKVS;
-
-    // Verify the user has enough balance
-    app.verify_user_api_key_has_balance(
-        &app,
-        &headers, 
-        cost
-    ).await?;
-
-    // Get the response from BulkVS
-    let response = app.bulkvs
-        .lock().await
-        .query_phone_number(&pii)
-        .context("Failed to get CNAM! from BulkVS!")?;
-
-    // Deduct the cost from the user's balance
-    app.deduct_cost_and_log(
-        &app,
-        &headers, 
-        ("Tele".to_string(), "BulkVS_CNAM".to_string(), PII::Phone, pii, cost),
+) -> Result<(Receipt, CacheInfo, Json<Formatted<BulkVSPhoneNumberResponse>>), AppError> {
+    let api = API::BulkVS;
+
+    // Get the response from BulkVS (or the cache), billing the user
+    let (receipt, cache, response) = app.billed_lookup(
+        &user,
+        CacheKey::new(api.provider(), api.operation(false), &PII::Phone, &pii),
+        fresh,
+        api.cost(false),
+        (api.category().to_string(), api.service(false).to_string(), PII::Phone, pii.clone()),
+        async {
+            Ok(app.bulkvs
+                .query_phone_number(&pii).await
+                .context("Failed to get CNAM! from BulkVS!")?)
+        }
     ).await?;
 
-    Ok(Json(response))
-}
\ No newline at end of file
+    Ok((receipt, cache, Json(format.apply(response, &app.fields))))
+}
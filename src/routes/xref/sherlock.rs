@@ -1,42 +1,255 @@
-use crate::helper::types::{ AppState, AppError, PII };
-use crate::apis::sherlock::SherlockResponse;
+use crate::helper::extract::Query;
+use crate::helper::types::{ AppState, AppError, PII, API };
+use crate::helper::auth::{ BillableUser, Receipt };
+use crate::helper::cache::{ CacheInfo, ForceFresh };
+use crate::helper::request_id;
+use crate::helper::findings::{ FormatParams, Formatted };
+use crate::helper::usernames::ValidationParams;
+use crate::apis::sherlock::{ SherlockFilter, SherlockResponse, SherlockSite, SiteStatus };
+use crate::apis::cache::{ CacheHit, CacheKey };
+use crate::apis::billing::Reservation;
 
 use axum::{
-    http::header::HeaderMap,
-    extract::State,
+    extract::{
+        State,
+        ws::{ Message, WebSocket, WebSocketUpgrade }
+    },
+    response::{
+        IntoResponse,
+        Response,
+        sse::{ Event, KeepAlive, Sse }
+    },
     Json
 };
 use anyhow::{ Result, Context };
+use serde::{ Serialize, Deserialize };
+use tokio::sync::mpsc;
 
 pub async fn sherlock ( 
     State(app): State<AppState>,
-    headers: HeaderMap,
+    Query(FormatParams { format }): Query<FormatParams>,
+    Query(filter): Query<SherlockFilter>,
+    Query(ValidationParams { validation }): Query<ValidationParams>,
+    user: BillableUser,
+    fresh: ForceFresh,
     username: String
-) -> Result<Json<SherlockResponse>, AppError> {
-    // Verify the API key
-    app.verify_api_key_header(&headers)?;
+) -> Result<(Receipt, CacheInfo, Json<Formatted<SherlockResponse>>), AppError> {
+    let api = API::Sherlock;
 
-    let cost = crate::COST_PER_XREF_SHERLOCK;
+    app.sherlock.check_username(&username, validation)?;
 
-    // Verify the user has enough balance
-    app.verify_user_api_key_has_balance(
-        &app,
-        &headers, 
-        cost
+    // Get the response from Sherlock (or the cache), billing the user
+    let (receipt, cache, response) = app.billed_lookup(
+        &user,
+        CacheKey::new(api.provider(), api.operation(false), &PII::Username, &username),
+        fresh,
+        api.cost(false),
+        (api.category().to_string(), api.service(false).to_string(), PII::Username, username.clone()),
+        async {
+            Ok(app.sherlock
+                .get_and_stringify_potential_profiles(username.clone()).await
+                .context("Failed to get Sherlock! from Sherlock!")?)
+        }
     ).await?;
 
-    // Get the response from BulkVS
-    let response = app.sherlock
-        .lock().await
-        .get_and_stringify_potential_profiles(username.clone(), true).await
-        .context("Failed to get Sherlock! from Sherlock!")?;
-
-    // Deduct the cost from the user's balance
-    app.deduct_cost_and_log(
-        &app,
-        &headers, 
-        ("Xref".to_string(), "Sherlock".to_string(), PII::Username, username, cost),
-    ).await?;
+    // The whole lookup is cached; the filter only narrows what's returned
+    Ok((receipt, cache, Json(format.apply(response.retain(&filter), &app.fields))))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamParams {
+    username: String
+}
+
+/// One message of a streamed Sherlock lookup.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamFrame {
+    /// A site's result, sent as soon as Sherlock reports it.
+    Site(SherlockSite),
+    /// Always the last frame.
+    Summary {
+        username: String,
+        found:    usize,
+        cost:     i32,
+        cached:   bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error:    Option<String>
+    }
+}
+impl StreamFrame {
+    fn name ( &self ) -> &'static str {
+        match self {
+            StreamFrame::Site { .. }    => "site",
+            StreamFrame::Summary { .. } => "summary"
+        }
+    }
+}
+
+/// Streams a Sherlock lookup as it runs, over a WebSocket if the request
+///  asks to upgrade and as Server-Sent Events otherwise. Each profile is
+///  forwarded as soon as Sherlock finds it, followed by a summary frame.
+///
+/// The stream starts at once. The user is charged when Sherlock reports
+///  its first site (or finishes having found none), so the summary frame,
+///  not `X-Credits-Charged`, carries the cost; a lookup that fails before
+///  then costs nothing. The finished lookup is cached like the buffered
+///  route's, even if the client left.
+pub async fn sherlock_stream (
+    State(app): State<AppState>,
+    Query(StreamParams { username }): Query<StreamParams>,
+    Query(filter): Query<SherlockFilter>,
+    Query(ValidationParams { validation }): Query<ValidationParams>,
+    user: BillableUser,
+    fresh: ForceFresh,
+    ws: Option<WebSocketUpgrade>
+) -> Result<Response, AppError> {
+    app.sherlock.check_username(&username, validation)?;
+
+    let (receipt, frames) = start_stream(&app, &user, fresh, username, filter).await?;
+
+    Ok(match ws {
+        Some(ws) => (receipt, ws.on_upgrade(|socket| forward_to_socket(socket, frames))).into_response(),
+        None => {
+            let events = futures_util::stream::unfold(frames, |mut frames| async move {
+                let frame = frames.recv().await?;
+
+                Some((Event::default().event(frame.name()).json_data(&frame), frames))
+            });
+
+            (receipt, Sse::new(events).keep_alive(KeepAlive::default())).into_response()
+        }
+    })
+}
+
+async fn start_stream (
+    app: &AppState,
+    user: &BillableUser,
+    fresh: ForceFresh,
+    username: String,
+    filter: SherlockFilter
+) -> Result<(Receipt, mpsc::UnboundedReceiver<StreamFrame>), AppError> {
+    let api = API::Sherlock;
+    let cost = api.cost(false);
+    let key = CacheKey::new(api.provider(), api.operation(false), &PII::Username, &username);
+    let usage = |service: String| (api.category().to_string(), service, PII::Username, username.clone());
+    let (frames, stream) = mpsc::unbounded_channel();
+
+    // Replay a cached lookup rather than running Sherlock again
+    let hit = match fresh {
+        ForceFresh(true) => None,
+        ForceFresh(false) => app.cache.get::<SherlockResponse>(&key).await
+    };
+    if let Some(CacheHit { value, .. }) = hit {
+        let reservation = user.reserve(app, app.pricing.cache_hit_cost(cost)).await?;
+        let receipt = app.commit_cost_and_log(reservation, usage(format!("{}_Cached", api.service(false)))).await?;
+
+        let value = value.retain(&filter);
+        for entry in value.results {
+            let _ = frames.send(StreamFrame::Site(entry));
+        }
+        let _ = frames.send(StreamFrame::Summary {
+            username,
+            found:  value.sites.len(),
+            cost:   receipt.cost,
+            cached: true,
+            error:  None
+        });
+
+        return Ok((receipt, stream));
+    }
+
+    app.sherlock.resilience.breaker.check()?;
+    let mut reservation = Some(user.reserve(app, cost).await?);
+    let usage = usage(api.service(false).to_string());
+
+    // Run detached, so the lookup finishes (and is cached) even if the client leaves
+    let (found, mut reported) = mpsc::unbounded_channel();
+    let lookup = tokio::spawn(request_id::propagate({
+        let (app, username) = (app.clone(), username.clone());
+
+        async move {
+            app.sherlock
+                .report_potential_profiles(username, found).await
+                .context("Failed to get Sherlock! from Sherlock!")
+        }
+    }));
+
+    tokio::spawn(request_id::propagate({
+        let app = app.clone();
+
+        async move {
+            let (mut receipt, mut error) = (None, None);
+            let mut count = 0;
+            while let Some(entry) = reported.recv().await {
+                // Charged as soon as Sherlock has produced something
+                if let Some(reservation) = reservation.take() {
+                    match charge(&app, reservation, usage.clone()).await {
+                        Ok(charged) => receipt = Some(charged),
+                        Err(e) => error = Some(e)
+                    }
+                }
+                // Charging failed, so nothing more is sent
+                if receipt.is_none() {
+                    continue;
+                }
+
+                if filter.matches(&entry) {
+                    if entry.status == SiteStatus::Claimed {
+                        count += 1;
+                    }
+
+                    let _ = frames.send(StreamFrame::Site(entry));
+                }
+            }
+
+            match lookup.await {
+                Ok(Ok(res)) => {
+                    app.cache.put(&key, &res).await;
+
+                    // It finished having found nothing, which still ran the lookup
+                    if let Some(reservation) = reservation.take() {
+                        match charge(&app, reservation, usage).await {
+                            Ok(charged) => receipt = Some(charged),
+                            Err(e) => error = Some(e)
+                        }
+                    }
+                },
+                // Failing before reporting anything leaves the reservation to be released
+                Ok(Err(e)) => error = error.or(Some(AppError::from(e).error().to_string())),
+                Err(e) => error = error.or(Some(format!("Sherlock task failed: {e}")))
+            }
+
+            let _ = frames.send(StreamFrame::Summary {
+                username,
+                found:  count,
+                cost:   receipt.map_or(0, |receipt| receipt.cost),
+                cached: false,
+                error
+            });
+        }
+    }));
+
+    // Nothing is charged yet; the stream settles its own reservation
+    Ok((Receipt { cost: 0 }, stream))
+}
+
+async fn charge ( app: &AppState, reservation: Reservation, usage: (String, String, PII, String) ) -> Result<Receipt, String> {
+    app.commit_cost_and_log(reservation, usage).await
+        .map_err(|e| AppError::from(e).error().to_string())
+}
+
+async fn forward_to_socket ( mut socket: WebSocket, mut frames: mpsc::UnboundedReceiver<StreamFrame> ) {
+    while let Some(frame) = frames.recv().await {
+        let Ok(text) = serde_json::to_string(&frame) else {
+            continue;
+        };
+
+        // The client left; the lookup still finishes and is cached
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+    }
 
-    Ok(Json(response))
-}
\ No newline at end of file
+    let _ = socket.send(Message::Close(None)).await;
+}